@@ -1,16 +1,43 @@
 //! Asset management.
 
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display, Formatter};
 
 /// Bundle metadata.
+///
+/// This is read from the `bundle.ron` at the root of a bundle.
 #[derive(Clone, Debug, Deserialize, PartialEq, Hash, Serialize)]
 pub struct Metadata {
     /// The name of the bundle.
     pub name: String,
     /// The version of the bundle.
     pub version: Version,
+    /// Paths to the characters the bundle provides, in roster order.
+    #[serde(default)]
+    pub characters: Vec<String>,
+    /// Paths to the stages the bundle provides.
+    #[serde(default)]
+    pub stages: Vec<String>,
+    /// The other bundles this bundle needs, by name, and the versions of them
+    /// it works with.
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionReq>,
+}
+
+impl Metadata {
+    /// Creates metadata for a bundle that provides nothing and depends on
+    /// nothing.
+    pub fn new(name: impl Into<String>, version: Version) -> Metadata {
+        Metadata {
+            name: name.into(),
+            version,
+            characters: Vec::new(),
+            stages: Vec::new(),
+            dependencies: BTreeMap::new(),
+        }
+    }
 }
 
 impl Display for Metadata {
@@ -12,6 +12,30 @@ pub struct Character {
     pub id: String,
     /// The states of the character.
     pub states: Vec<State>,
+    /// The alternate palettes of the character.
+    ///
+    /// The colours the sprites are drawn in are always the first palette, so
+    /// these are picked starting from `1`.
+    #[serde(default)]
+    pub palettes: Vec<Palette>,
+}
+
+/// An alternate palette of a [`Character`].
+///
+/// A palette is a colour-remap texture: a cube of colours, with a square
+/// slice for each level of blue laid out left to right. The texture is `n`
+/// pixels tall and `n * n` wide, and the pixel at `(b * n + r, g)` is the
+/// colour that replaces the colour `(r, g, b)`, in steps of `1 / (n - 1)`.
+/// Colours between the steps are blended from the closest ones.
+///
+/// Starting from a texture that leaves every colour as it is, a palette can
+/// be made by recolouring it like any other image.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Palette {
+    /// The name of the palette, as shown to players.
+    pub name: String,
+    /// A path to the colour-remap texture.
+    pub remap: String,
 }
 
 /// A state.
@@ -26,10 +50,22 @@ pub struct State {
 }
 
 /// A single frame in a [`State`].
+///
+/// Boxes are relative to the origin of the character, facing right, in the
+/// same units as positions. Sprites are one unit tall.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Frame {
     /// The sprite to display for this frame.
     pub sprite: Option<Sprite>,
+    /// The boxes that hit the other player.
+    #[serde(default)]
+    pub hitboxes: Vec<Rect>,
+    /// The boxes the other player can hit.
+    #[serde(default)]
+    pub hurtboxes: Vec<Rect>,
+    /// The box that keeps the players from walking through each other.
+    #[serde(default)]
+    pub pushbox: Option<Rect>,
 }
 
 /// A [`Frame`]'s sprite.
@@ -38,8 +74,15 @@ pub struct Frame {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Sprite {
     /// A path to the texture of the state.
+    ///
+    /// If the sprite has a `region`, this is a path to a [`Sheet`](crate::Sheet) instead.
     pub texture: String,
+    /// The name of the region of the [`Sheet`](crate::Sheet) to use.
+    #[serde(default)]
+    pub region: Option<String>,
     /// The source rectangle of the image.
+    ///
+    /// This is ignored for sprites with a `region`.
     #[serde(default = "default_rect")]
     pub src: Rect,
     /// The transformations to be applied to the image, relative to the origin.
@@ -0,0 +1,71 @@
+//! The in-battle HUD.
+//!
+//! Everything on the HUD is placed in screen space, not in the world. The
+//! origin is the center of the screen, `y` points up and the screen is `1`
+//! unit tall, so the top edge is at `0.5`. How far the screen reaches to the
+//! sides depends on the aspect ratio; a 16:9 screen reaches about `0.89`.
+
+use crate::Rect;
+use glam::f32::Vec2;
+use serde::{Deserialize, Serialize};
+
+/// The layout of the HUD.
+///
+/// Elements that belong to a player are placed for player one. Player two's
+/// are mirrored across the middle of the screen.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Layout {
+    /// A path to a [`Sheet`](crate::Sheet) with the digits used to draw
+    /// numbers, in regions named `"0"` to `"9"`.
+    pub digits: String,
+    /// The health bar.
+    pub health: Bar,
+    /// The meter bar.
+    pub meter: Bar,
+    /// The seconds left in the match.
+    pub timer: Number,
+    /// The number of the match in the series.
+    pub round: Number,
+    /// The matches each player has won in the series.
+    pub wins: Number,
+    /// The combo a player is landing on the other.
+    pub combo: Combo,
+}
+
+/// A bar showing how full something is.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Bar {
+    /// The bounds of the bar when it is full.
+    pub rect: Rect,
+    /// The colour of the bar behind the fill.
+    pub back: [f32; 4],
+    /// The colour of the fill, which grows from the left edge of `rect`.
+    pub fill: [f32; 4],
+}
+
+/// A number.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Number {
+    /// The center of the number.
+    pub pos: Vec2,
+    /// The height of a digit. Digits are as wide as their regions allow.
+    pub height: f32,
+    /// The colour to multiply the digits with.
+    #[serde(default = "default_color")]
+    pub color: [f32; 4],
+}
+
+/// A combo counter.
+///
+/// This is only shown once a combo is at least two hits long.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Combo {
+    /// The number of hits in the combo.
+    pub hits: Number,
+    /// The damage the combo has dealt.
+    pub damage: Number,
+}
+
+fn default_color() -> [f32; 4] {
+    [1., 1., 1., 1.]
+}
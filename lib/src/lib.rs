@@ -2,8 +2,13 @@
 
 pub mod assets;
 pub mod character;
+pub mod hud;
 pub mod rect;
+pub mod sheet;
+pub mod stage;
 
 pub use assets::Metadata;
 pub use character::Character;
 pub use rect::Rect;
+pub use sheet::Sheet;
+pub use stage::Stage;
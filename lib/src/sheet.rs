@@ -0,0 +1,186 @@
+//! Sprite sheets.
+
+use crate::Rect;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A sprite sheet.
+///
+/// A sheet is a single texture holding many sprites. Each sprite is a named
+/// [`Region`] of the texture, which can be listed one by one or sliced out of
+/// a [`Grid`]. Frames reference a sprite by the path to the sheet and the
+/// name of the region.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Sheet {
+    /// A path to the texture of the sheet.
+    pub texture: String,
+    /// Named regions of the texture.
+    #[serde(default)]
+    pub regions: BTreeMap<String, Region>,
+    /// A grid to slice the texture into regions with.
+    #[serde(default)]
+    pub grid: Option<Grid>,
+}
+
+impl Sheet {
+    /// Finds a region by name.
+    ///
+    /// Regions listed by name take priority over cells of the grid.
+    pub fn region(&self, name: &str) -> Option<Region> {
+        if let Some(region) = self.regions.get(name) {
+            return Some(*region);
+        }
+
+        let grid = self.grid.as_ref()?;
+        let index = grid.names.iter().position(|n| n == name)?;
+
+        Some(grid.cell(index as u32))
+    }
+
+    /// The names of every region in the sheet.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        let cells = self.grid.iter().flat_map(|grid| grid.names.iter());
+
+        self.regions.keys().chain(cells).map(String::as_str)
+    }
+}
+
+/// A region of a texture, in pixels.
+///
+/// The origin is the top-left corner of the texture, like in an image
+/// editor.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash, Serialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Converts the region to a source rectangle for a texture of the given
+    /// size, where the whole texture is `0` to `1`.
+    pub fn normalize(&self, width: u32, height: u32) -> Rect {
+        let (width, height) = (width as f32, height as f32);
+
+        Rect::new_wh(
+            self.x as f32 / width,
+            self.y as f32 / height,
+            self.width as f32 / width,
+            self.height as f32 / height,
+        )
+    }
+
+    /// Checks if the region fits in a texture of the given size.
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        // regions come from asset files, so the ends may not even fit in a u32
+        let right = self.x.checked_add(self.width);
+        let bottom = self.y.checked_add(self.height);
+
+        right.is_some_and(|r| r <= width) && bottom.is_some_and(|b| b <= height)
+    }
+}
+
+/// A grid of same-sized cells.
+///
+/// Cells are named in reading order: left to right, then top to bottom.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Grid {
+    /// The width of a cell, in pixels.
+    pub width: u32,
+    /// The height of a cell, in pixels.
+    pub height: u32,
+    /// How many cells are in a row.
+    pub columns: u32,
+    /// The names of the cells.
+    pub names: Vec<String>,
+}
+
+impl Grid {
+    /// The region of the cell at `index`, in reading order.
+    pub fn cell(&self, index: u32) -> Region {
+        let columns = self.columns.max(1);
+
+        Region {
+            // saturated cells are out of any texture, so they don't fit
+            x: (index % columns).saturating_mul(self.width),
+            y: (index / columns).saturating_mul(self.height),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid() {
+        let sheet = Sheet {
+            texture: "/img/sheet.png".to_owned(),
+            regions: [(
+                "idle".to_owned(),
+                Region {
+                    x: 0,
+                    y: 64,
+                    width: 16,
+                    height: 16,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            grid: Some(Grid {
+                width: 32,
+                height: 64,
+                columns: 2,
+                names: vec!["idle".into(), "walk1".into(), "walk2".into()],
+            }),
+        };
+
+        assert_eq!(sheet.region("idle").unwrap().y, 64);
+        assert_eq!(
+            sheet.region("walk2"),
+            Some(Region {
+                x: 0,
+                y: 64,
+                width: 32,
+                height: 64,
+            })
+        );
+        assert_eq!(sheet.region("walk3"), None);
+        assert_eq!(
+            sheet.region("walk1").unwrap().normalize(64, 128),
+            Rect::new_wh(0.5, 0., 0.5, 0.5)
+        );
+    }
+
+    #[test]
+    fn fits() {
+        let region = Region {
+            x: 16,
+            y: 0,
+            width: 16,
+            height: 32,
+        };
+        assert!(region.fits(32, 32));
+        assert!(!region.fits(31, 32));
+
+        // a crafted region that would overflow
+        let region = Region {
+            x: u32::MAX,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert!(!region.fits(64, 64));
+
+        let region = Region {
+            x: 0,
+            y: u32::MAX,
+            width: 1,
+            height: u32::MAX,
+        };
+        assert!(!region.fits(64, 64));
+    }
+}
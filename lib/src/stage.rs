@@ -0,0 +1,47 @@
+//! Stages.
+
+use glam::f32::Vec2;
+use serde::{Deserialize, Serialize};
+
+/// A stage definition.
+///
+/// Stages are laid out in world units, like characters. The middle of the
+/// stage is at `0`, and the floor players stand on is at a height of `0`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Stage {
+    /// The name of the stage, as shown to players.
+    pub name: String,
+    /// The width of the stage. Players can't walk past its edges, and the
+    /// camera doesn't look past them.
+    pub width: f32,
+    /// How high the floor is drawn in the layers, from their bottom edge.
+    #[serde(default)]
+    pub floor: f32,
+    /// The background layers, back to front.
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+}
+
+/// A background layer of a [`Stage`].
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Layer {
+    /// A path to the texture of the layer.
+    pub texture: String,
+    /// The size the texture is drawn at.
+    pub size: Vec2,
+    /// How far the layer is moved from its place, which is centered on the
+    /// middle of the stage with its bottom edge `floor` below the floor.
+    #[serde(default)]
+    pub offset: Vec2,
+    /// How much the layer moves as the camera does.
+    ///
+    /// At `1` the layer is fixed to the stage, like the floor. Lower factors
+    /// make the layer seem further away, and at `0` it follows the camera
+    /// and stays put on screen, like the sky.
+    #[serde(default = "default_parallax")]
+    pub parallax: f32,
+}
+
+fn default_parallax() -> f32 {
+    1.
+}
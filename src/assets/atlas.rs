@@ -0,0 +1,248 @@
+//! Texture atlases.
+//!
+//! Characters are easiest to author with a texture for every frame, but each
+//! texture has to be bound on its own to be drawn. [`build`] packs the loose
+//! textures of every character in a bundle into an atlas per character, with
+//! a [`Sheet`] that names the region of each texture after its old path, and
+//! rewrites the characters to use the sheet instead.
+//!
+//! This is a build step, run by `pack --atlas`. The bundle directory itself is
+//! left alone, but the textures that went into an atlas are left out of the
+//! pack, unless something else still uses them.
+
+use super::Bundle;
+
+use crate::render::decode_image;
+
+use bftd_lib::sheet::Region;
+use bftd_lib::{Character, Rect, Sheet, Stage};
+
+use anyhow::Error;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use std::path::Path;
+
+/// The widest an atlas is made, in pixels.
+const MAX_WIDTH: u32 = 4096;
+
+/// The space left between textures in an atlas, in pixels, so filtering
+/// doesn't bleed neighbours into each other.
+const PADDING: u32 = 1;
+
+/// The atlases built for a bundle by [`build`].
+#[derive(Debug, Default)]
+pub struct Atlases {
+    /// The files to add to the bundle, and the rewritten characters that
+    /// replace the old ones, by path.
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// The textures that were packed into an atlas and aren't used by any
+    /// character or stage anymore, so they can be left out.
+    pub replaced: BTreeSet<String>,
+}
+
+/// Builds atlases for the characters a bundle declares.
+///
+/// Only textures that are in the bundle and are used whole are packed, and
+/// characters with fewer than two of them are left as they are.
+pub fn build(bundle: &Bundle) -> Result<Atlases, Error> {
+    let mut files = BTreeMap::new();
+    // every texture packed, and every texture still used loose after
+    let mut packed = BTreeSet::new();
+    let mut used = BTreeSet::new();
+
+    for path in bundle.metadata().stages.iter() {
+        if let Some(bytes) = bundle.read(path)? {
+            let stage: Stage = ron::de::from_bytes(&bytes)?;
+            used.extend(stage.layers.iter().map(|layer| normalize(&layer.texture)));
+        }
+    }
+
+    for path in bundle.metadata().characters.iter() {
+        let bytes = match bundle.read(path)? {
+            Some(bytes) => bytes,
+            // declared in another bundle
+            None => continue,
+        };
+        let mut character: Character = ron::de::from_bytes(&bytes)?;
+
+        // whatever is left loose once the character is rewritten
+        let mut uses = |character: &Character| {
+            let sprites = character
+                .states
+                .iter()
+                .flat_map(|state| state.frames.iter())
+                .filter_map(|frame| frame.sprite.as_ref())
+                .filter(|sprite| sprite.region.is_none())
+                .map(|sprite| normalize(&sprite.texture));
+            let palettes = character.palettes.iter().map(|p| normalize(&p.remap));
+
+            used.extend(sprites.chain(palettes));
+        };
+
+        let whole = Rect::new_wh(0., 0., 1., 1.);
+        let textures = character
+            .states
+            .iter()
+            .flat_map(|state| state.frames.iter())
+            .filter_map(|frame| frame.sprite.as_ref())
+            .filter(|sprite| sprite.region.is_none() && sprite.src == whole)
+            .map(|sprite| sprite.texture.clone())
+            .filter(|texture| bundle.contains(texture))
+            .collect::<BTreeSet<_>>();
+
+        if textures.len() < 2 {
+            uses(&character);
+            continue;
+        }
+
+        let images = textures
+            .iter()
+            .map(|texture| {
+                let bytes = bundle.read(texture)?.unwrap_or_default();
+                decode_image(Cursor::new(bytes))
+                    .map_err(|e| e.context(format!("cannot load \"{}\"", texture)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let sizes = images
+            .iter()
+            .map(|i| (i.width(), i.height()))
+            .collect::<Vec<_>>();
+        let (width, height, regions) = layout(&sizes);
+
+        let mut atlas = image::RgbaImage::new(width, height);
+        for (image, region) in images.iter().zip(regions.iter()) {
+            image::imageops::replace(&mut atlas, image, region.x as i64, region.y as i64);
+        }
+
+        let stem = Path::new(path)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(&character.id)
+            .to_owned();
+        let texture_path = format!("/atlas/{}.png", stem);
+        let sheet_path = format!("/atlas/{}.ron", stem);
+
+        let sheet = Sheet {
+            texture: texture_path.clone(),
+            regions: textures.iter().cloned().zip(regions).collect(),
+            grid: None,
+        };
+
+        for state in character.states.iter_mut() {
+            for sprite in state.frames.iter_mut().filter_map(|f| f.sprite.as_mut()) {
+                if textures.contains(&sprite.texture) && sprite.src == whole {
+                    sprite.region =
+                        Some(std::mem::replace(&mut sprite.texture, sheet_path.clone()));
+                }
+            }
+        }
+
+        uses(&character);
+        packed.extend(textures.iter().map(|texture| normalize(texture)));
+
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(atlas)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+
+        let pretty = ron::ser::PrettyConfig::default();
+
+        files.insert(normalize(&texture_path), png);
+        files.insert(
+            normalize(&sheet_path),
+            ron::ser::to_string_pretty(&sheet, pretty.clone())?.into_bytes(),
+        );
+        files.insert(
+            normalize(path),
+            ron::ser::to_string_pretty(&character, pretty)?.into_bytes(),
+        );
+
+        info!(
+            "packed {} textures of {} into a {}x{} atlas",
+            textures.len(),
+            character.id,
+            width,
+            height
+        );
+    }
+
+    Ok(Atlases {
+        files,
+        replaced: packed.difference(&used).cloned().collect(),
+    })
+}
+
+/// Lays out textures of the given sizes in an atlas.
+///
+/// Textures are put on shelves, tallest first. Returns the size of the atlas
+/// and the region of each texture, in the order they were given.
+fn layout(sizes: &[(u32, u32)]) -> (u32, u32, Vec<Region>) {
+    let widest = sizes.iter().map(|s| s.0).max().unwrap_or(0);
+    let area = sizes
+        .iter()
+        .map(|(w, h)| (w + PADDING) as u64 * (h + PADDING) as u64)
+        .sum::<u64>();
+
+    // aim for a square atlas
+    let width = ((area as f64).sqrt().ceil() as u32)
+        .next_power_of_two()
+        .min(MAX_WIDTH)
+        .max(widest);
+
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let mut regions = vec![Region::default(); sizes.len()];
+    let (mut x, mut y, mut shelf) = (0, 0, 0);
+
+    for i in order {
+        let (w, h) = sizes[i];
+
+        if x > 0 && x + w > width {
+            x = 0;
+            y += shelf + PADDING;
+            shelf = 0;
+        }
+
+        regions[i] = Region {
+            x,
+            y,
+            width: w,
+            height: h,
+        };
+
+        x += w + PADDING;
+        shelf = shelf.max(h);
+    }
+
+    (width, y + shelf, regions)
+}
+
+fn normalize(path: &str) -> String {
+    super::normalize(path).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_fits() {
+        let sizes = [(64, 128), (300, 20), (64, 128), (10, 10), (128, 64), (1, 1)];
+        let (width, height, regions) = layout(&sizes);
+
+        for (i, a) in regions.iter().enumerate() {
+            assert_eq!((a.width, a.height), sizes[i]);
+            assert!(a.fits(width, height));
+
+            for b in regions[i + 1..].iter() {
+                let apart = a.x + a.width <= b.x
+                    || b.x + b.width <= a.x
+                    || a.y + a.height <= b.y
+                    || b.y + b.height <= a.y;
+                assert!(apart, "{:?} overlaps {:?}", a, b);
+            }
+        }
+    }
+}
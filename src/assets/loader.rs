@@ -0,0 +1,431 @@
+//! Asynchronous loading of characters.
+//!
+//! Loading a character means reading its definition, then reading and
+//! compiling each of its scripts and reading and decoding each of its
+//! textures and palettes, going through the sprite sheets that some of them
+//! are in. A [`Loader`] does all of that on the [`TaskPool`], one task per
+//! file, so a slow disk or a big texture doesn't stall the event loop. Only
+//! the GPU upload of each texture happens on the main thread, in
+//! [`Loader::poll`].
+//!
+//! Everything loaded ends up in the [`Vfs`] cache. Once loading is done, the
+//! [`Fsm`]s are built with [`Vfs::load_character`] like usual, which only hits
+//! the cache.
+
+use super::{normalize, read, Bundle, Digest, Vfs};
+
+use crate::battle::fsm::Fsm;
+use crate::battle::script::{Engine, AST};
+use crate::render::{decode_image, Texture};
+use crate::Context;
+
+use bftd_lib::{Character, Sheet};
+
+use anyhow::Error;
+use bevy_tasks::TaskPool;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hasher;
+use std::io::Cursor;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Loads characters in the background.
+pub struct Loader {
+    characters: Vec<String>,
+    bundles: Vec<Arc<Bundle>>,
+    engine: Arc<Engine>,
+    tx: Sender<Result<Loaded, Error>>,
+    rx: Receiver<Result<Loaded, Error>>,
+    // every file a task was spawned for, and what it was loaded as
+    requested: HashMap<String, Kind>,
+    loaded: usize,
+    // keeps loaded assets alive until the characters are built
+    held: Vec<Arc<dyn Any + Send + Sync>>,
+}
+
+/// How far along a [`Loader`] is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// How many files were loaded.
+    pub loaded: usize,
+    /// How many files are known to need loading.
+    ///
+    /// This grows as characters are read and their scripts and textures are
+    /// found, so it is only final once loading is done.
+    pub total: usize,
+}
+
+impl Progress {
+    /// The ratio of files loaded, from `0` to `1`.
+    pub fn ratio(&self) -> f32 {
+        if self.total > 0 {
+            self.loaded as f32 / self.total as f32
+        } else {
+            1.
+        }
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.loaded, self.total)
+    }
+}
+
+struct Loaded {
+    path: String,
+    digest: u64,
+    modified: Option<SystemTime>,
+    data: Data,
+}
+
+enum Data {
+    Character(Character),
+    Script(AST),
+    Sheet(Sheet),
+    Image(image::RgbaImage),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Character,
+    Script,
+    Sheet,
+    Image,
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Character => "character",
+            Kind::Script => "script",
+            Kind::Sheet => "sprite sheet",
+            Kind::Image => "image",
+        })
+    }
+}
+
+impl Loader {
+    /// Starts loading the characters at `characters` from the bundles mounted
+    /// in `vfs`.
+    pub fn new(pool: &TaskPool, vfs: &Vfs, engine: Arc<Engine>, characters: Vec<String>) -> Loader {
+        let (tx, rx) = mpsc::channel();
+
+        let mut loader = Loader {
+            characters,
+            bundles: vfs.bundles.clone(),
+            engine,
+            tx,
+            rx,
+            requested: HashMap::new(),
+            loaded: 0,
+            held: Vec::new(),
+        };
+
+        for path in loader.characters.clone() {
+            loader.request(pool, &path, Kind::Character);
+        }
+
+        loader
+    }
+
+    /// Collects the files loaded since the last poll.
+    ///
+    /// Characters that were read start the loading of their scripts, sheets,
+    /// textures and palettes, sheets start the loading of their texture, and
+    /// decoded textures are uploaded to the GPU. Returns an error if any file
+    /// failed to load, or if a file is used as two different kinds of asset,
+    /// like both a sprite sheet and an image.
+    pub fn poll(&mut self, cx: &mut Context, vfs: &mut Vfs) -> Result<(), Error> {
+        let render = &cx.render;
+
+        self.collect(&cx.task_pool, vfs, |image| render.create_texture(image))
+    }
+
+    /// Collects the files loaded since the last poll, like [`Loader::poll`],
+    /// turning decoded images into textures with `upload`.
+    fn collect<F>(&mut self, pool: &TaskPool, vfs: &mut Vfs, mut upload: F) -> Result<(), Error>
+    where
+        F: FnMut(&image::RgbaImage) -> Texture,
+    {
+        while let Ok(loaded) = self.rx.try_recv() {
+            let Loaded {
+                path,
+                digest,
+                modified,
+                data,
+            } = loaded?;
+
+            self.loaded += 1;
+
+            match data {
+                Data::Character(character) => {
+                    for state in character.states.iter() {
+                        if let Some(script) = &state.script {
+                            self.request(pool, script, Kind::Script);
+                        }
+
+                        for sprite in state.frames.iter().filter_map(|f| f.sprite.as_ref()) {
+                            let kind = match sprite.region {
+                                Some(_) => Kind::Sheet,
+                                None => Kind::Image,
+                            };
+
+                            self.request(pool, &sprite.texture, kind);
+                        }
+                    }
+
+                    for palette in character.palettes.iter() {
+                        self.request(pool, &palette.remap, Kind::Image);
+                    }
+
+                    self.insert(vfs, &path, character, digest, modified);
+                }
+                Data::Script(ast) => self.insert(vfs, &path, ast, digest, modified),
+                Data::Sheet(sheet) => {
+                    self.request(pool, &sheet.texture, Kind::Image);
+                    self.insert(vfs, &path, sheet, digest, modified);
+                }
+                Data::Image(image) => {
+                    let texture = upload(&image);
+                    self.insert(vfs, &path, texture, digest, modified);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How far along loading is.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            loaded: self.loaded,
+            total: self.requested.len(),
+        }
+    }
+
+    /// Checks if every file was loaded.
+    pub fn is_done(&self) -> bool {
+        self.loaded == self.requested.len()
+    }
+
+    /// Builds the loaded characters, in the order they were given.
+    ///
+    /// This should only be called once the loader [is done](Loader::is_done).
+    pub fn finish(&self, cx: &mut Context, vfs: &mut Vfs) -> Result<Vec<Fsm>, Error> {
+        self.characters
+            .iter()
+            .map(|path| vfs.load_character(cx, path))
+            .collect()
+    }
+
+    fn request(&mut self, pool: &TaskPool, path: &str, kind: Kind) {
+        let path = normalize(path).to_owned();
+
+        // the cache only has room for one asset per path
+        match self.requested.get(&path) {
+            Some(&requested) if requested == kind => return,
+            Some(&requested) => {
+                let _ = self.tx.send(Err(anyhow!(
+                    "\"{}\" is used as both a {} and a {}",
+                    path,
+                    requested,
+                    kind
+                )));
+                return;
+            }
+            None => {
+                self.requested.insert(path.clone(), kind);
+            }
+        }
+
+        let bundles = self.bundles.clone();
+        let engine = self.engine.clone();
+        let tx = self.tx.clone();
+
+        pool.spawn(async move {
+            let loaded = load(&bundles, &engine, &path, kind)
+                .map_err(|e| e.context(format!("cannot load \"{}\"", path)));
+
+            // the loader was dropped, nobody wants this anymore
+            let _ = tx.send(loaded);
+        })
+        .detach();
+    }
+
+    fn insert<T>(
+        &mut self,
+        vfs: &mut Vfs,
+        path: &str,
+        data: T,
+        digest: u64,
+        modified: Option<SystemTime>,
+    ) where
+        T: Send + Sync + 'static,
+    {
+        let data = Arc::new(data);
+        vfs.insert(path, data.clone(), digest, modified);
+        self.held.push(data);
+    }
+}
+
+/// Reads and decodes a file. This runs on the task pool.
+fn load(bundles: &[Arc<Bundle>], engine: &Engine, path: &str, kind: Kind) -> Result<Loaded, Error> {
+    let (bytes, bundle) = read(bundles, path)?;
+    let modified = bundle.modified(path);
+
+    let mut digest = Digest::new();
+    digest.write(&bytes);
+
+    let data = match kind {
+        Kind::Character => Data::Character(ron::de::from_bytes(&bytes)?),
+        Kind::Script => Data::Script(engine.compile(std::str::from_utf8(&bytes)?)?),
+        Kind::Sheet => Data::Sheet(ron::de::from_bytes(&bytes)?),
+        Kind::Image => Data::Image(decode_image(Cursor::new(&bytes))?),
+    };
+
+    Ok(Loaded {
+        path: path.to_owned(),
+        digest: digest.finish(),
+        modified,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assets::tests::{write, TempDir};
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Polls `loader` until it is done, recording the progress after every
+    /// poll.
+    fn wait(loader: &mut Loader, pool: &TaskPool, vfs: &mut Vfs) -> Result<Vec<Progress>, Error> {
+        let start = Instant::now();
+        let mut progress = vec![loader.progress()];
+
+        while !loader.is_done() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "timed out loading"
+            );
+
+            loader.collect(pool, vfs, |image| {
+                Texture::headless(image.width(), image.height())
+            })?;
+            progress.push(loader.progress());
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        Ok(progress)
+    }
+
+    fn vfs(dir: &TempDir, files: &[(&str, &str)]) -> Vfs {
+        let path = dir.path().join("bundle");
+        write(
+            &path,
+            &[(
+                "bundle.ron",
+                r#"Metadata(name: "Loader", version: "0.1.0")"#,
+            )],
+        );
+        write(&path, files);
+
+        std::fs::create_dir_all(path.join("img")).unwrap();
+        image::RgbaImage::new(2, 2)
+            .save(path.join("img/a.png"))
+            .unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.mount(Bundle::new(path).unwrap()).unwrap();
+        vfs
+    }
+
+    fn loader(pool: &TaskPool, vfs: &Vfs, character: &str) -> Loader {
+        Loader::new(
+            pool,
+            vfs,
+            Arc::new(Engine::new()),
+            vec![character.to_owned()],
+        )
+    }
+
+    #[test]
+    fn progress() {
+        let dir = TempDir::new("loader");
+        let pool = TaskPool::new();
+        let mut vfs = vfs(
+            &dir,
+            &[
+                (
+                    "characters/a.ron",
+                    r#"Character(id: "a", states: [(
+                        name: "idle",
+                        frames: [(sprite: Some((texture: "/img/a.png")))],
+                        script: Some("/scripts/idle.rhai"),
+                    )])"#,
+                ),
+                ("scripts/idle.rhai", "fn onupdate() {}"),
+            ],
+        );
+
+        let mut loader = loader(&pool, &vfs, "/characters/a.ron");
+        let progress = wait(&mut loader, &pool, &mut vfs).unwrap();
+
+        // only the character is known to start with, then its script and
+        // texture are found
+        assert_eq!(
+            progress[0],
+            Progress {
+                loaded: 0,
+                total: 1
+            }
+        );
+        assert!(progress.windows(2).all(|w| w[0].total <= w[1].total));
+        assert_eq!(
+            progress.last(),
+            Some(&Progress {
+                loaded: 3,
+                total: 3
+            })
+        );
+
+        // done only once everything is
+        assert!(progress[..progress.len() - 1].iter().all(|p| p.loaded < 3));
+
+        assert!(vfs.digest("/img/a.png").is_some());
+        assert!(vfs.digest("scripts/idle.rhai").is_some());
+    }
+
+    #[test]
+    fn errors() {
+        let dir = TempDir::new("loader");
+        let pool = TaskPool::new();
+        let mut vfs = vfs(
+            &dir,
+            &[(
+                "characters/a.ron",
+                r#"Character(id: "a", states: [
+                    (name: "idle", frames: [(sprite: Some((texture: "/img/a.png")))]),
+                    (name: "walk", frames: [(sprite: Some((texture: "img/a.png", region: Some("walk"))))]),
+                ])"#,
+            )],
+        );
+
+        let mut missing = loader(&pool, &vfs, "/characters/missing.ron");
+        assert!(wait(&mut missing, &pool, &mut vfs).is_err());
+
+        // the image can't be both an image and a sheet
+        let mut conflict = loader(&pool, &vfs, "/characters/a.ron");
+        let e = wait(&mut conflict, &pool, &mut vfs).unwrap_err();
+        assert!(e.to_string().contains("both"), "{}", e);
+    }
+}
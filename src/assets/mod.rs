@@ -0,0 +1,849 @@
+//! Asset management.
+
+mod atlas;
+mod loader;
+pub mod pack;
+pub mod validate;
+
+pub use loader::{Loader, Progress};
+
+use bftd_lib::hud::Layout;
+use bftd_lib::{Metadata, Rect, Sheet};
+
+use anyhow::Error;
+
+use pack::Pack;
+
+use crate::battle::fsm::{Frame, Fsm, Key, State};
+use crate::battle::script::AST;
+use crate::battle::{stage, Hud, Stage};
+use crate::render::{Font, Sprite, Texture};
+use crate::Context;
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{Cursor, ErrorKind, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+
+/// An asset's type.
+pub type Asset<T> = Arc<T>;
+
+/// An asset bundle.
+///
+/// A bundle is either a directory with a `bundle.ron` describing it at the
+/// root, or a [`Pack`] of such a directory. Bundles are mounted in a [`Vfs`]
+/// to load assets from them.
+pub struct Bundle {
+    metadata: Metadata,
+    source: Source,
+}
+
+enum Source {
+    Dir(PathBuf),
+    Pack(Pack),
+}
+
+impl Bundle {
+    /// Opens a [`Bundle`] from a directory or a pack file.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Bundle, Error> {
+        let path = path.into();
+
+        let source = if path.is_file() {
+            Source::Pack(Pack::open(&path)?)
+        } else {
+            Source::Dir(path)
+        };
+
+        // load the metadata
+        let metadata = match &source {
+            Source::Dir(path) => {
+                let file = File::open(path.join("bundle.ron"))
+                    .map_err(|e| anyhow!("cannot open bundle at {}: {}", path.display(), e))?;
+                ron::de::from_reader(file)?
+            }
+            Source::Pack(pack) => {
+                let bytes = pack
+                    .read("bundle.ron")?
+                    .ok_or_else(|| anyhow!("pack {} has no bundle.ron", pack.path().display()))?;
+                ron::de::from_bytes(&bytes)?
+            }
+        };
+
+        Ok(Bundle { metadata, source })
+    }
+
+    /// Reads a file from the bundle.
+    ///
+    /// Returns `None` if the bundle doesn't have the file, and an error if the
+    /// path leads out of the bundle.
+    pub fn read(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let path = relative(path)?;
+
+        match &self.source {
+            Source::Dir(dir) => match std::fs::read(dir.join(path)) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Source::Pack(pack) => pack.read(path),
+        }
+    }
+
+    /// Checks if the bundle has a file.
+    pub fn contains(&self, path: &str) -> bool {
+        let path = match relative(path) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+
+        match &self.source {
+            Source::Dir(dir) => dir.join(path).is_file(),
+            Source::Pack(pack) => pack.contains(path),
+        }
+    }
+
+    /// The paths of every file in the bundle, except for the metadata.
+    pub fn files(&self) -> Result<Vec<String>, Error> {
+        let mut files = Vec::new();
+
+        match &self.source {
+            Source::Dir(dir) => walk(dir, dir, &mut files)?,
+            Source::Pack(pack) => files.extend(pack.files().map(String::from)),
+        }
+
+        files.retain(|path| path != "bundle.ron");
+        files.sort();
+
+        Ok(files)
+    }
+
+    /// When a file in the bundle was last modified.
+    ///
+    /// Returns `None` if the bundle doesn't have the file, or if it is in a
+    /// pack. Packs are meant for release, so they are never reloaded.
+    pub fn modified(&self, path: &str) -> Option<SystemTime> {
+        let path = relative(path).ok()?;
+
+        match &self.source {
+            Source::Dir(dir) => std::fs::metadata(dir.join(path))
+                .and_then(|m| m.modified())
+                .ok(),
+            Source::Pack(_) => None,
+        }
+    }
+
+    /// The metadata of the bundle.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The directory or pack file the bundle is in.
+    pub fn path(&self) -> &Path {
+        match &self.source {
+            Source::Dir(dir) => dir,
+            Source::Pack(pack) => pack.path(),
+        }
+    }
+}
+
+/// A virtual filesystem of [`Bundle`]s.
+///
+/// Bundles are mounted in priority order. When several bundles have a file at
+/// the same path, the one mounted last is used, so a mod can override assets
+/// from the bundles under it as well as add its own.
+#[derive(Default)]
+pub struct Vfs {
+    // lowest priority first, shared with loading tasks
+    bundles: Vec<Arc<Bundle>>,
+    cache: HashMap<String, Weak<dyn Any + Send + Sync>>,
+    digests: HashMap<String, u64>,
+    // when each loaded file was modified, to notice changes
+    modified: HashMap<String, Option<SystemTime>>,
+    // the files each loaded character was built from
+    uses: HashMap<String, Vec<String>>,
+}
+
+impl Vfs {
+    /// Creates a new, empty `Vfs`.
+    pub fn new() -> Vfs {
+        Vfs::default()
+    }
+
+    /// Mounts a bundle on top of the others.
+    ///
+    /// Returns an error if a bundle with the same name is already mounted.
+    pub fn mount(&mut self, bundle: Bundle) -> Result<(), Error> {
+        if let Some(other) = self.find(&bundle.metadata.name) {
+            bail!(
+                "cannot mount {}, {} is already mounted",
+                bundle.metadata,
+                other.metadata
+            );
+        }
+
+        info!(
+            "mounted bundle {} from {}",
+            bundle.metadata,
+            bundle.path().display()
+        );
+
+        self.bundles.push(Arc::new(bundle));
+        Ok(())
+    }
+
+    /// Checks that the dependencies of every bundle are mounted under it and
+    /// have compatible versions.
+    pub fn check(&self) -> Result<(), Error> {
+        for (i, bundle) in self.bundles.iter().enumerate() {
+            for (name, req) in bundle.metadata.dependencies.iter() {
+                let j = match self.bundles.iter().position(|b| &b.metadata.name == name) {
+                    Some(j) => j,
+                    None => bail!(
+                        "{} depends on {} {}, which is not mounted",
+                        bundle.metadata,
+                        name,
+                        req
+                    ),
+                };
+                let dependency = &self.bundles[j];
+
+                if !req.matches(&dependency.metadata.version) {
+                    bail!(
+                        "{} depends on {} {}, but {} is mounted",
+                        bundle.metadata,
+                        name,
+                        req,
+                        dependency.metadata
+                    );
+                }
+
+                if j > i {
+                    bail!(
+                        "{} depends on {}, so it must be mounted after it",
+                        bundle.metadata,
+                        dependency.metadata
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The mounted bundles, lowest priority first.
+    pub fn bundles(&self) -> impl DoubleEndedIterator<Item = &Bundle> {
+        self.bundles.iter().map(Arc::as_ref)
+    }
+
+    /// The metadata of the mounted bundles, lowest priority first.
+    pub fn metadata(&self) -> Vec<Metadata> {
+        self.bundles.iter().map(|b| b.metadata.clone()).collect()
+    }
+
+    /// The characters provided by all bundles, in mount order.
+    ///
+    /// A character declared by more than one bundle is only listed once.
+    pub fn characters(&self) -> Vec<String> {
+        self.declared(|m| &m.characters)
+    }
+
+    /// The stages provided by all bundles, in mount order.
+    pub fn stages(&self) -> Vec<String> {
+        self.declared(|m| &m.stages)
+    }
+
+    /// The bundle a file would be loaded from.
+    pub fn provider(&self, path: &str) -> Option<&Bundle> {
+        self.bundles().rev().find(|b| b.contains(path))
+    }
+
+    /// Every file in the mounted bundles, along with the bundle that supplies
+    /// it.
+    pub fn assets(&self) -> Result<BTreeMap<String, &Bundle>, Error> {
+        let mut assets = BTreeMap::new();
+
+        // higher priority bundles replace lower ones
+        for bundle in self.bundles() {
+            for path in bundle.files()? {
+                assets.insert(path, bundle);
+            }
+        }
+
+        Ok(assets)
+    }
+
+    /// Reads a file from the highest priority bundle that has it.
+    pub fn read(&self, path: &str) -> Result<(Vec<u8>, &Bundle), Error> {
+        read(&self.bundles, path)
+    }
+
+    /// Loads a file from the filesystem.
+    ///
+    /// This loads from the cache if the resource is cached.
+    pub fn load<T>(&mut self, cx: &mut Context, path: &str) -> Result<Asset<T>, Error>
+    where
+        T: Loadable + Send + Sync + 'static,
+    {
+        let path = normalize(path);
+
+        if let Some(cached) = self.cache.get(path).and_then(|s| s.upgrade()) {
+            if let Ok(cached) = cached.downcast() {
+                return Ok(cached);
+            }
+        }
+
+        let (bytes, bundle) = self.read(path)?;
+        let modified = bundle.modified(path);
+
+        debug!(
+            "loading file \"{}\" from bundle {}...",
+            path, bundle.metadata.name
+        );
+
+        let data = T::load(cx, Cursor::new(&bytes)).map(Arc::new)?;
+
+        let mut digest = Digest::new();
+        digest.write(&bytes);
+        self.insert(path, data.clone(), digest.finish(), modified);
+
+        Ok(data)
+    }
+
+    /// Puts an asset loaded elsewhere in the cache, as if it was loaded with
+    /// [`Vfs::load`].
+    fn insert<T>(&mut self, path: &str, data: Asset<T>, digest: u64, modified: Option<SystemTime>)
+    where
+        T: Send + Sync + 'static,
+    {
+        let path = normalize(path);
+
+        self.digests.insert(path.to_owned(), digest);
+        self.modified.insert(path.to_owned(), modified);
+
+        let data: Arc<dyn Any + Send + Sync + 'static> = data;
+        self.cache.insert(path.to_owned(), Arc::downgrade(&data));
+    }
+
+    /// Loads a character.
+    ///
+    /// The returned [`Fsm`] carries a digest of the character definition and
+    /// its scripts, so peers can check they are playing the same character.
+    /// Palettes only change how the character looks, so they are left out.
+    pub fn load_character(&mut self, cx: &mut Context, path: &str) -> Result<Fsm, Error> {
+        let character = self.load::<bftd_lib::Character>(cx, path)?;
+        let mut uses = vec![normalize(path).to_owned()];
+
+        let mut digest = Digest::new();
+        digest.write_u64(self.digest(path).unwrap_or_default());
+
+        let mut states = Vec::new();
+        for state in character.states.iter() {
+            // load script if necessary
+            let script = match &state.script {
+                Some(path) => {
+                    let ast = self.load::<AST>(cx, path)?;
+                    digest.write_u64(self.digest(path).unwrap_or_default());
+                    uses.push(normalize(path).to_owned());
+
+                    Some(AST::clone(&ast))
+                }
+                None => None,
+            };
+
+            let mut frames = Vec::new();
+            for frame in state.frames.iter() {
+                // load sprite if necessary
+                let sprite = match &frame.sprite {
+                    Some(sprite) => Some(self.load_sprite(cx, sprite, &mut uses)?),
+                    None => None,
+                };
+
+                frames.push(Frame {
+                    sprite,
+                    hitboxes: frame.hitboxes.clone(),
+                    hurtboxes: frame.hurtboxes.clone(),
+                    pushbox: frame.pushbox.clone(),
+                });
+            }
+
+            states.push(State {
+                name: Key::from(state.name.as_str()),
+                frames,
+                script,
+            });
+        }
+
+        let mut palettes = Vec::new();
+        for palette in character.palettes.iter() {
+            let texture = self.load::<Texture>(cx, &palette.remap)?;
+            uses.push(normalize(&palette.remap).to_owned());
+
+            palettes.push(Texture::clone(&texture));
+        }
+
+        self.uses.insert(normalize(path).to_owned(), uses);
+
+        Ok(Fsm::new(states)
+            .with_palettes(palettes)
+            .with_digest(digest.finish()))
+    }
+
+    /// Loads a HUD from its layout.
+    pub fn load_hud(&mut self, cx: &mut Context, path: &str) -> Result<Hud, Error> {
+        let layout = self.load::<Layout>(cx, path)?;
+        let sheet = self.load::<Sheet>(cx, &layout.digits)?;
+        let texture = self.load::<Texture>(cx, &sheet.texture)?;
+
+        let mut regions = [Default::default(); 10];
+        for (digit, region) in regions.iter_mut().enumerate() {
+            *region = sheet.region(&digit.to_string()).ok_or_else(|| {
+                anyhow!("sheet \"{}\" has no region \"{}\"", layout.digits, digit)
+            })?;
+        }
+
+        Ok(Hud::new(
+            Layout::clone(&layout),
+            Texture::clone(&texture),
+            regions,
+        ))
+    }
+
+    /// Loads a stage and the textures of its layers.
+    ///
+    /// The returned [`Stage`] carries a digest of the stage definition, so
+    /// peers can check they are fighting on the same stage.
+    pub fn load_stage(&mut self, cx: &mut Context, path: &str) -> Result<Stage, Error> {
+        let definition = self.load::<bftd_lib::Stage>(cx, path)?;
+
+        let mut layers = Vec::new();
+        for layer in definition.layers.iter() {
+            let texture = self.load::<Texture>(cx, &layer.texture)?;
+
+            layers.push(stage::Layer {
+                texture: Texture::clone(&texture),
+                rect: Rect::new_wh(
+                    layer.offset.x - layer.size.x / 2.,
+                    layer.offset.y - definition.floor,
+                    layer.size.x,
+                    layer.size.y,
+                ),
+                parallax: layer.parallax,
+            });
+        }
+
+        let digest = self.digest(path).unwrap_or_default();
+        Ok(Stage::new(definition.width, layers).with_digest(digest))
+    }
+
+    /// Loads the texture of a frame's sprite, from a [`Sheet`] if the sprite
+    /// names a region.
+    fn load_sprite(
+        &mut self,
+        cx: &mut Context,
+        sprite: &bftd_lib::character::Sprite,
+        uses: &mut Vec<String>,
+    ) -> Result<Sprite, Error> {
+        let (path, src) = match &sprite.region {
+            Some(region) => {
+                let sheet = self.load::<Sheet>(cx, &sprite.texture)?;
+                uses.push(normalize(&sprite.texture).to_owned());
+
+                let region = sheet.region(region).ok_or_else(|| {
+                    anyhow!("sheet \"{}\" has no region \"{}\"", sprite.texture, region)
+                })?;
+
+                (sheet.texture.clone(), Some(region))
+            }
+            None => (sprite.texture.clone(), None),
+        };
+
+        let texture = self.load::<Texture>(cx, &path)?;
+        uses.push(normalize(&path).to_owned());
+
+        // FIXME: possibly bad if we avoid asset handling Arcs
+        let mut out = Sprite::new(Texture::clone(&texture));
+        out.set_src(match src {
+            Some(region) => region.normalize(texture.width(), texture.height()),
+            None => sprite.src.clone(),
+        });
+
+        Ok(out)
+    }
+
+    /// Finds the loaded files that changed since they were loaded.
+    ///
+    /// Changed files are dropped from the cache, so loading them again reads
+    /// the new contents. A file counts as changed if it was modified, or if
+    /// a different file now overrides it.
+    pub fn changes(&mut self) -> Vec<String> {
+        let mut changed = Vec::new();
+
+        for (path, modified) in self.modified.iter_mut() {
+            let now = self
+                .bundles
+                .iter()
+                .rev()
+                .find(|b| b.contains(path))
+                .and_then(|b| b.modified(path));
+
+            if now != *modified {
+                *modified = now;
+                changed.push(path.clone());
+            }
+        }
+
+        for path in changed.iter() {
+            self.cache.remove(path);
+        }
+
+        changed.sort();
+        changed
+    }
+
+    /// The loaded characters built from any of the files in `paths`.
+    pub fn dependents(&self, paths: &[String]) -> Vec<String> {
+        let mut dependents = self
+            .uses
+            .iter()
+            .filter(|(_, uses)| uses.iter().any(|u| paths.contains(u)))
+            .map(|(character, _)| character.clone())
+            .collect::<Vec<_>>();
+
+        dependents.sort();
+        dependents
+    }
+
+    /// The digest of the raw contents of a loaded file.
+    ///
+    /// Returns `None` if the file hasn't been loaded yet.
+    pub fn digest(&self, path: &str) -> Option<u64> {
+        self.digests.get(normalize(path)).copied()
+    }
+
+    fn find(&self, name: &str) -> Option<&Bundle> {
+        self.bundles().find(|b| b.metadata.name == name)
+    }
+
+    fn declared(&self, list: impl Fn(&Metadata) -> &Vec<String>) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+
+        for bundle in self.bundles() {
+            for path in list(&bundle.metadata) {
+                if !paths.iter().any(|p| normalize(p) == normalize(path)) {
+                    paths.push(path.clone());
+                }
+            }
+        }
+
+        paths
+    }
+}
+
+/// Reads a file from the highest priority bundle in `bundles` that has it.
+fn read<'a>(bundles: &'a [Arc<Bundle>], path: &str) -> Result<(Vec<u8>, &'a Bundle), Error> {
+    for bundle in bundles.iter().rev() {
+        if let Some(bytes) = bundle.read(path)? {
+            return Ok((bytes, &**bundle));
+        }
+    }
+
+    Err(anyhow!(
+        "no mounted bundle has file \"{}\"",
+        normalize(path)
+    ))
+}
+
+/// Clips the leading slash off of a path, if there is any.
+fn normalize(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+/// Normalizes a path, and checks that it stays in the bundle it is read from.
+///
+/// Only plain names may go between the slashes, so there is no way out of the
+/// bundle with `..`.
+fn relative(path: &str) -> Result<&str, Error> {
+    let relative = normalize(path);
+    let plain = Path::new(relative)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+
+    if !plain {
+        bail!("\"{}\" leads out of the bundle", path);
+    }
+
+    Ok(relative)
+}
+
+/// Collects the paths of the files under `dir`, relative to `root`.
+fn walk(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            walk(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            // paths in bundles always use forward slashes
+            let relative = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            files.push(relative);
+        }
+    }
+
+    Ok(())
+}
+
+/// A stable digest of asset contents.
+///
+/// This is 64-bit FNV-1a. Unlike the hashers in [`std`], the result is the
+/// same across builds and platforms, so it can be compared between peers.
+#[derive(Clone, Copy, Debug)]
+pub struct Digest(u64);
+
+impl Digest {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    /// Creates a new, empty `Digest`.
+    pub fn new() -> Digest {
+        Digest(Digest::OFFSET_BASIS)
+    }
+}
+
+impl Default for Digest {
+    fn default() -> Digest {
+        Digest::new()
+    }
+}
+
+impl Hasher for Digest {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(Digest::PRIME);
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        // always little-endian so digests agree between platforms
+        self.write(&i.to_le_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// An asset that can be loaded from a [`Vfs`].
+pub trait Loadable: Sized {
+    /// Loads an asset from a stream.
+    fn load<T>(cx: &mut Context, stream: T) -> Result<Self, Error>
+    where
+        T: Read + Seek;
+}
+
+impl Loadable for String {
+    fn load<T>(_cx: &mut Context, mut stream: T) -> Result<Self, Error>
+    where
+        T: Read,
+    {
+        let mut buf = String::new();
+
+        stream.read_to_string(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+impl Loadable for AST {
+    fn load<T>(cx: &mut Context, stream: T) -> Result<Self, Error>
+    where
+        T: Read + Seek,
+    {
+        let source = String::load(cx, stream)?;
+
+        cx.script.compile(&source).map_err(From::from)
+    }
+}
+
+impl Loadable for Texture {
+    fn load<R>(cx: &mut Context, stream: R) -> Result<Self, Error>
+    where
+        R: Read + Seek,
+    {
+        cx.render.load_texture(stream)
+    }
+}
+
+impl Loadable for Font {
+    fn load<R>(_cx: &mut Context, stream: R) -> Result<Self, Error>
+    where
+        R: Read,
+    {
+        Font::parse(stream)
+    }
+}
+
+macro_rules! impl_ron {
+    ($T:ty) => {
+        impl Loadable for $T {
+            fn load<R>(_cx: &mut Context, stream: R) -> Result<Self, Error>
+            where
+                R: Read,
+            {
+                ron::de::from_reader(stream).map_err(From::from)
+            }
+        }
+    };
+}
+
+impl_ron!(bftd_lib::Character);
+impl_ron!(Sheet);
+impl_ron!(Layout);
+impl_ron!(bftd_lib::Stage);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A temporary directory, removed once dropped.
+    pub(super) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(super) fn new(name: &str) -> TempDir {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+            let path = std::env::temp_dir().join(format!(
+                "bftd-{}-{}-{}",
+                name,
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+
+            TempDir(path)
+        }
+
+        pub(super) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes `files` under `dir`.
+    pub(super) fn write(dir: &Path, files: &[(&str, &str)]) {
+        for (file, contents) in files {
+            let file = dir.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, contents).unwrap();
+        }
+    }
+
+    /// Writes a bundle to a directory in `dir`.
+    fn bundle(dir: &TempDir, name: &str, metadata: &str, files: &[(&str, &str)]) -> Bundle {
+        let path = dir.path().join(name);
+
+        write(&path, files);
+        write(&path, &[("bundle.ron", metadata)]);
+
+        Bundle::new(path).unwrap()
+    }
+
+    fn core(dir: &TempDir) -> Bundle {
+        bundle(
+            dir,
+            "core",
+            r#"Metadata(name: "Core", version: "0.1.0", characters: ["/characters/a.ron"])"#,
+            &[
+                ("characters/a.ron", "core a"),
+                ("core/idle.rhai", "core idle"),
+            ],
+        )
+    }
+
+    fn with_mod(dir: &TempDir, req: &str) -> Bundle {
+        bundle(
+            dir,
+            "mod",
+            &format!(
+                r#"Metadata(name: "Mod", version: "1.0.0", characters: ["/characters/b.ron", "characters/a.ron"], dependencies: {{ "Core": "{}" }})"#,
+                req
+            ),
+            &[
+                ("characters/b.ron", "mod b"),
+                ("core/idle.rhai", "mod idle"),
+            ],
+        )
+    }
+
+    #[test]
+    fn overrides() {
+        let dir = TempDir::new("vfs");
+        let mut vfs = Vfs::new();
+        vfs.mount(core(&dir)).unwrap();
+        vfs.mount(with_mod(&dir, "^0.1")).unwrap();
+        vfs.check().unwrap();
+
+        assert_eq!(vfs.read("/core/idle.rhai").unwrap().0, b"mod idle");
+        assert_eq!(vfs.read("characters/a.ron").unwrap().0, b"core a");
+        assert!(vfs.read("characters/c.ron").is_err());
+
+        assert_eq!(vfs.characters(), ["/characters/a.ron", "/characters/b.ron"]);
+
+        let assets = vfs.assets().unwrap();
+        let provider = |path: &str| assets[path].metadata().name.as_str();
+        assert_eq!(assets.len(), 3);
+        assert_eq!(provider("characters/a.ron"), "Core");
+        assert_eq!(provider("characters/b.ron"), "Mod");
+        assert_eq!(provider("core/idle.rhai"), "Mod");
+    }
+
+    #[test]
+    fn dependencies() {
+        let dir = TempDir::new("vfs");
+        let mut vfs = Vfs::new();
+        vfs.mount(with_mod(&dir, "^0.1")).unwrap();
+        assert!(vfs.check().is_err());
+
+        // the dependency is there, but mounted over the mod
+        vfs.mount(core(&dir)).unwrap();
+        assert!(vfs.check().is_err());
+        assert!(vfs.mount(core(&dir)).is_err());
+
+        let mut vfs = Vfs::new();
+        vfs.mount(core(&dir)).unwrap();
+        vfs.mount(with_mod(&dir, "^0.2")).unwrap();
+        assert!(vfs.check().is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        let dir = TempDir::new("vfs");
+        write(dir.path(), &[("secret", "secret")]);
+        let core = core(&dir);
+
+        assert!(core.read("/characters/a.ron").unwrap().is_some());
+
+        // the secret is right next to the bundle, but out of reach
+        assert!(core.read("../secret").is_err());
+        assert!(core.read("/characters/../../secret").is_err());
+        assert!(!core.contains("characters/../../secret"));
+        assert!(core.modified("../secret").is_none());
+    }
+}
@@ -0,0 +1,352 @@
+//! Packed bundles.
+//!
+//! A pack is a whole [`Bundle`] in a single file, which is easier to pass
+//! around than a directory. It starts with an index of every file in the
+//! bundle, followed by the contents of the files:
+//!
+//! ```text
+//! magic    b"BFTDPACK"
+//! version  u32
+//! count    u32
+//! entries  count times:
+//!     path_len  u32
+//!     path      path_len bytes of utf-8
+//!     offset    u64, from the start of the pack
+//!     len       u64
+//! data     the contents of each entry
+//! ```
+//!
+//! All numbers are little-endian. Paths have no leading slash and always use
+//! forward slashes, like they are written in bundles.
+
+use super::{atlas, Bundle};
+
+use anyhow::Error;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The bytes every pack starts with.
+pub const MAGIC: &[u8; 8] = b"BFTDPACK";
+
+/// The version of the format written by [`write`].
+const VERSION: u32 = 1;
+
+/// An opened pack.
+///
+/// Only the index is kept in memory. Files are read from the pack as they
+/// are needed.
+pub struct Pack {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    offset: u64,
+    len: u64,
+}
+
+impl Pack {
+    /// Opens a pack, reading its index.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Pack, Error> {
+        let path = path.into();
+        let file = File::open(&path)?;
+        // nothing in the index can point past the end of the pack
+        let size = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            bail!("{} is not a pack", path.display());
+        }
+
+        let version = read_u32(&mut file)?;
+        if version != VERSION {
+            bail!(
+                "{} is a version {} pack, only version {} is supported",
+                path.display(),
+                version,
+                VERSION
+            );
+        }
+
+        let count = read_u32(&mut file)?;
+        let mut entries = BTreeMap::new();
+
+        for _ in 0..count {
+            let name_len = read_u32(&mut file)?;
+            if u64::from(name_len) > size {
+                bail!("{} has a path longer than the pack", path.display());
+            }
+
+            let mut name = vec![0u8; name_len as usize];
+            file.read_exact(&mut name)?;
+            let name = String::from_utf8(name)?;
+
+            let entry = Entry {
+                offset: read_u64(&mut file)?,
+                len: read_u64(&mut file)?,
+            };
+
+            match entry.offset.checked_add(entry.len) {
+                Some(end) if end <= size => (),
+                _ => bail!("{} has {} past the end of the pack", path.display(), name),
+            }
+
+            entries.insert(name, entry);
+        }
+
+        Ok(Pack { path, entries })
+    }
+
+    /// Reads a file from the pack.
+    ///
+    /// Returns `None` if the pack doesn't have the file.
+    pub fn read(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let entry = match self.entries.get(path) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+
+        let mut bytes = vec![0u8; entry.len as usize];
+        file.read_exact(&mut bytes)?;
+
+        Ok(Some(bytes))
+    }
+
+    /// Checks if the pack has a file.
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    /// The paths of every file in the pack.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// The path of the pack file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Packs the bundle in the directory `dir` into the file `out`.
+///
+/// If `atlas` is set, the loose textures of the bundle's characters are
+/// packed into atlases along the way, and the textures that went into an
+/// atlas are left out of the pack. See [`atlas::build`].
+///
+/// `out` can't be in the bundle, or packing the bundle again would pack the
+/// old pack along with it.
+pub fn write(dir: impl Into<PathBuf>, out: impl AsRef<Path>, atlas: bool) -> Result<(), Error> {
+    let bundle = Bundle::new(dir)?;
+    let out = out.as_ref();
+
+    if is_inside(out, bundle.path())? {
+        bail!(
+            "cannot write the pack to {}, it is inside the bundle at {}",
+            out.display(),
+            bundle.path().display()
+        );
+    }
+
+    let atlas::Atlases {
+        files: built,
+        replaced,
+    } = if atlas {
+        atlas::build(&bundle)?
+    } else {
+        Default::default()
+    };
+
+    let mut files = bundle.files()?;
+    files.retain(|path| !replaced.contains(path));
+    files.extend(built.keys().cloned());
+    files.sort();
+    files.dedup();
+    files.insert(0, "bundle.ron".to_owned());
+
+    // the data starts right after the index
+    let index_len = MAGIC.len() + 8 + files.iter().map(|path| 4 + path.len() + 16).sum::<usize>();
+
+    let mut contents = Vec::with_capacity(files.len());
+    let mut offset = index_len as u64;
+
+    let mut out = BufWriter::new(File::create(out)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(files.len() as u32).to_le_bytes())?;
+
+    for path in files.iter() {
+        let bytes = match built.get(path) {
+            Some(bytes) => bytes.clone(),
+            None => bundle
+                .read(path)?
+                .ok_or_else(|| anyhow!("{} disappeared while packing", path))?,
+        };
+
+        out.write_all(&(path.len() as u32).to_le_bytes())?;
+        out.write_all(path.as_bytes())?;
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+
+        offset += bytes.len() as u64;
+        contents.push(bytes);
+    }
+
+    for bytes in contents.iter() {
+        out.write_all(bytes)?;
+    }
+
+    out.flush()?;
+
+    info!(
+        "packed {} files of bundle {} ({} bytes)",
+        files.len(),
+        bundle.metadata(),
+        offset
+    );
+
+    Ok(())
+}
+
+/// Checks if `path` is inside `dir`, whether or not `path` exists yet.
+fn is_inside(path: &Path, dir: &Path) -> Result<bool, Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    Ok(parent.canonicalize()?.starts_with(dir.canonicalize()?))
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assets::tests::{write as write_files, TempDir};
+
+    #[test]
+    fn roundtrip() {
+        let temp = TempDir::new("pack");
+        let dir = temp.path().join("bundle");
+        let out = temp.path().join("bundle.pack");
+
+        let files = [
+            (
+                "bundle.ron",
+                r#"Metadata(name: "Packed", version: "0.1.0")"#,
+            ),
+            ("characters/a.ron", "a"),
+            ("core/idle.rhai", "idle"),
+        ];
+        write_files(&dir, &files);
+
+        write(&dir, &out, false).unwrap();
+
+        let packed = Bundle::new(&out).unwrap();
+        let unpacked = Bundle::new(&dir).unwrap();
+
+        assert_eq!(packed.metadata(), unpacked.metadata());
+        assert_eq!(packed.files().unwrap(), unpacked.files().unwrap());
+        assert_eq!(packed.read("/core/idle.rhai").unwrap().unwrap(), b"idle");
+        assert!(packed.contains("characters/a.ron"));
+        assert!(packed.read("characters/b.ron").unwrap().is_none());
+
+        assert!(Pack::open(dir.join("core/idle.rhai")).is_err());
+
+        // packing into the bundle would pack the pack the next time around
+        let inside = dir.join("characters/bundle.pack");
+        assert!(write(&dir, &inside, false).is_err());
+        assert!(!inside.exists());
+    }
+
+    #[test]
+    fn leaves_out_atlased_textures() {
+        let temp = TempDir::new("pack");
+        let dir = temp.path().join("bundle");
+        let out = temp.path().join("bundle.pack");
+
+        let files = [
+            (
+                "bundle.ron",
+                r#"Metadata(name: "Atlas", version: "0.1.0", characters: ["/characters/a.ron"], stages: ["/stages/s.ron"])"#,
+            ),
+            (
+                "characters/a.ron",
+                r#"Character(id: "a", states: [(
+                    name: "idle",
+                    frames: [
+                        (sprite: Some((texture: "/img/a.png"))),
+                        (sprite: Some((texture: "/img/b.png"))),
+                    ],
+                    script: None,
+                )])"#,
+            ),
+            (
+                "stages/s.ron",
+                r#"Stage(name: "S", width: 4., layers: [(texture: "/img/b.png", size: (4., 2.))])"#,
+            ),
+        ];
+        write_files(&dir, &files);
+        std::fs::create_dir_all(dir.join("img")).unwrap();
+        for image in ["a", "b"] {
+            image::RgbaImage::new(4, 4)
+                .save(dir.join(format!("img/{}.png", image)))
+                .unwrap();
+        }
+
+        write(&dir, &out, true).unwrap();
+
+        let packed = Bundle::new(&out).unwrap();
+        assert!(packed.contains("atlas/a.png"));
+        assert!(packed.contains("atlas/a.ron"));
+
+        // only the stage still uses b.png loose
+        assert!(!packed.contains("img/a.png"));
+        assert!(packed.contains("img/b.png"));
+    }
+
+    #[test]
+    fn corrupt_index() {
+        let temp = TempDir::new("pack");
+        let header = [&MAGIC[..], &VERSION.to_le_bytes(), &1u32.to_le_bytes()].concat();
+
+        // a path far longer than the pack
+        let out = temp.path().join("long.pack");
+        std::fs::write(&out, [&header[..], &u32::MAX.to_le_bytes()].concat()).unwrap();
+        assert!(Pack::open(&out).is_err());
+
+        // a file that runs past the end of the pack
+        let out = temp.path().join("past.pack");
+        let entry = [
+            &1u32.to_le_bytes()[..],
+            b"a",
+            &0u64.to_le_bytes(),
+            &u64::MAX.to_le_bytes(),
+        ]
+        .concat();
+        std::fs::write(&out, [header, entry].concat()).unwrap();
+        assert!(Pack::open(&out).is_err());
+    }
+}
@@ -0,0 +1,486 @@
+//! Bundle validation.
+//!
+//! The game bails on the first broken asset it loads, which makes fixing a
+//! bundle slow. [`validate`] goes through everything the mounted bundles
+//! declare instead and reports every problem it finds, along with the file
+//! the problem is in.
+
+use super::{normalize, Bundle, Vfs};
+
+use crate::battle::hud;
+use crate::battle::script::Engine;
+
+use bftd_lib::hud::Layout;
+use bftd_lib::{Character, Sheet, Stage};
+
+use anyhow::Error;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::io::Cursor;
+use std::path::Path;
+
+/// A problem found in a bundle.
+#[derive(Clone, Debug)]
+pub struct Problem {
+    /// The path of the file with the problem.
+    pub path: String,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Validates the mounted bundles.
+///
+/// This checks the dependencies of the bundles, and that every declared
+/// character parses, has an `idle` state, and only uses textures, sheet regions
+/// and scripts that exist and load. Scripts may only `state.change` to states
+/// of the characters that use them, and palettes have to be colour-remap
+/// textures. If there is a HUD, its layout has to parse and its digits sheet
+/// has to have every digit. Declared stages have to parse and their layer
+/// textures have to load.
+pub fn validate(vfs: &Vfs, engine: &Engine) -> Vec<Problem> {
+    let mut validator = Validator {
+        vfs,
+        engine,
+        problems: Vec::new(),
+        textures: HashMap::new(),
+        sheets: HashMap::new(),
+        scripts: HashMap::new(),
+    };
+
+    if let Err(e) = vfs.check() {
+        validator.problem("bundle.ron", e);
+    }
+
+    for path in vfs.characters() {
+        validator.character(&path);
+    }
+
+    if vfs.provider(hud::LAYOUT_PATH).is_some() {
+        validator.hud(hud::LAYOUT_PATH);
+    }
+
+    for path in vfs.stages() {
+        validator.stage(&path);
+    }
+
+    validator.problems
+}
+
+/// Mounts the bundles at `paths`, in order, and validates them.
+///
+/// A bundle that cannot be mounted is reported as a problem with its path, and
+/// the rest are validated without it.
+pub fn validate_bundles<P: AsRef<Path>>(paths: &[P], engine: &Engine) -> Vec<Problem> {
+    let mut vfs = Vfs::new();
+    let mut problems = Vec::new();
+
+    for path in paths.iter().map(AsRef::as_ref) {
+        if let Err(e) = Bundle::new(path).and_then(|bundle| vfs.mount(bundle)) {
+            problems.push(Problem {
+                path: path.display().to_string(),
+                message: format!("cannot mount bundle: {:#}", e),
+            });
+        }
+    }
+
+    problems.extend(validate(&vfs, engine));
+    problems
+}
+
+struct Validator<'a> {
+    vfs: &'a Vfs,
+    engine: &'a Engine,
+    problems: Vec<Problem>,
+    // the size of each checked texture, if it decoded
+    textures: HashMap<String, Option<(u32, u32)>>,
+    // each checked sheet, if it parsed
+    sheets: HashMap<String, Option<Sheet>>,
+    // the states each checked script changes to, if it compiled
+    scripts: HashMap<String, Option<Vec<String>>>,
+}
+
+impl<'a> Validator<'a> {
+    fn problem(&mut self, path: &str, message: impl Display) {
+        self.problems.push(Problem {
+            path: path.trim_start_matches('/').to_owned(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Reads a file, telling a file that isn't there apart from one that
+    /// can't be read.
+    fn read(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        for bundle in self.vfs.bundles().rev() {
+            if let Some(bytes) = bundle.read(path)? {
+                return Ok(Some(bytes));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn character(&mut self, path: &str) {
+        let character = match self.read(path) {
+            Ok(Some(bytes)) => ron::de::from_bytes::<Character>(&bytes),
+            Ok(None) => return self.problem(path, "character is declared, but does not exist"),
+            Err(e) => return self.problem(path, format!("cannot read character: {:#}", e)),
+        };
+
+        let character = match character {
+            Ok(character) => character,
+            Err(e) => return self.problem(path, format!("invalid character: {}", e)),
+        };
+
+        let mut names = HashSet::new();
+        for state in character.states.iter() {
+            if !names.insert(state.name.as_str()) {
+                self.problem(
+                    path,
+                    format!("state `{}` is defined more than once", state.name),
+                );
+            }
+        }
+
+        if !names.contains("idle") {
+            self.problem(path, "no `idle` state; every character starts in it");
+        }
+
+        for state in character.states.iter() {
+            for sprite in state.frames.iter().filter_map(|f| f.sprite.as_ref()) {
+                if self.vfs.provider(&sprite.texture).is_none() {
+                    let kind = match sprite.region {
+                        Some(_) => "sheet",
+                        None => "texture",
+                    };
+
+                    self.problem(
+                        path,
+                        format!(
+                            "state `{}` uses missing {} {}",
+                            state.name, kind, sprite.texture
+                        ),
+                    );
+                    continue;
+                }
+
+                match &sprite.region {
+                    Some(region) => self.sprite(path, &state.name, &sprite.texture, region),
+                    None => {
+                        self.texture(&sprite.texture);
+                    }
+                }
+            }
+
+            let script = match &state.script {
+                Some(script) => script,
+                None => continue,
+            };
+
+            if self.vfs.provider(script).is_none() {
+                self.problem(
+                    path,
+                    format!("state `{}` uses missing script {}", state.name, script),
+                );
+                continue;
+            }
+
+            for target in self.script(script) {
+                if !names.contains(target.as_str()) {
+                    self.problem(
+                        script,
+                        format!(
+                            "changes to state `{}`, which {} (used by its state `{}`) does not have",
+                            target, character.id, state.name
+                        ),
+                    );
+                }
+            }
+        }
+
+        for palette in character.palettes.iter() {
+            if self.vfs.provider(&palette.remap).is_none() {
+                self.problem(
+                    path,
+                    format!(
+                        "palette `{}` uses missing texture {}",
+                        palette.name, palette.remap
+                    ),
+                );
+                continue;
+            }
+
+            if let Some((width, height)) = self.texture(&palette.remap) {
+                if height < 2 || width != height * height {
+                    self.problem(
+                        &palette.remap,
+                        format!(
+                            "a {}x{} texture is not a colour-remap texture; it has to be n*n pixels wide and n tall",
+                            width, height
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks that a sprite's region is in its sheet, and in the sheet's
+    /// texture.
+    fn sprite(&mut self, path: &str, state: &str, sheet_path: &str, name: &str) {
+        let sheet = match self.sheet(sheet_path) {
+            Some(sheet) => sheet,
+            None => return,
+        };
+
+        let region = match sheet.region(name) {
+            Some(region) => region,
+            None => {
+                return self.problem(
+                    path,
+                    format!(
+                        "state `{}` uses region `{}`, which {} does not have",
+                        state, name, sheet_path
+                    ),
+                )
+            }
+        };
+
+        if let Some((width, height)) = self
+            .textures
+            .get(normalize(&sheet.texture))
+            .copied()
+            .flatten()
+        {
+            if !region.fits(width, height) {
+                self.problem(
+                    sheet_path,
+                    format!(
+                        "region `{}` does not fit in the {}x{} texture",
+                        name, width, height
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks that a HUD layout parses and that its digits sheet has every
+    /// digit.
+    fn hud(&mut self, path: &str) {
+        let layout = match self
+            .vfs
+            .read(path)
+            .and_then(|(bytes, _)| Ok(ron::de::from_bytes::<Layout>(&bytes)?))
+        {
+            Ok(layout) => layout,
+            Err(e) => return self.problem(path, format!("invalid HUD layout: {}", e)),
+        };
+
+        if self.vfs.provider(&layout.digits).is_none() {
+            return self.problem(path, format!("HUD uses missing sheet {}", layout.digits));
+        }
+
+        let sheet = match self.sheet(&layout.digits) {
+            Some(sheet) => sheet,
+            None => return,
+        };
+
+        for digit in 0..10 {
+            if sheet.region(&digit.to_string()).is_none() {
+                self.problem(&layout.digits, format!("sheet has no digit `{}`", digit));
+            }
+        }
+    }
+
+    /// Checks that a stage parses and that the textures of its layers decode.
+    fn stage(&mut self, path: &str) {
+        let stage = match self.read(path) {
+            Ok(Some(bytes)) => ron::de::from_bytes::<Stage>(&bytes),
+            Ok(None) => return self.problem(path, "stage is declared, but does not exist"),
+            Err(e) => return self.problem(path, format!("cannot read stage: {:#}", e)),
+        };
+
+        let stage = match stage {
+            Ok(stage) => stage,
+            Err(e) => return self.problem(path, format!("invalid stage: {}", e)),
+        };
+
+        if stage.width <= 0. {
+            self.problem(path, "stage width has to be positive");
+        }
+
+        for layer in stage.layers.iter() {
+            if self.vfs.provider(&layer.texture).is_some() {
+                self.texture(&layer.texture);
+            } else {
+                self.problem(
+                    path,
+                    format!("stage layer uses missing texture {}", layer.texture),
+                );
+            }
+        }
+    }
+
+    /// Checks that a sheet parses and that its texture decodes.
+    fn sheet(&mut self, path: &str) -> Option<Sheet> {
+        if let Some(sheet) = self.sheets.get(normalize(path)) {
+            return sheet.clone();
+        }
+
+        let sheet = match self
+            .vfs
+            .read(path)
+            .and_then(|(bytes, _)| Ok(ron::de::from_bytes::<Sheet>(&bytes)?))
+        {
+            Ok(sheet) => {
+                if self.vfs.provider(&sheet.texture).is_some() {
+                    self.texture(&sheet.texture);
+                } else {
+                    self.problem(
+                        path,
+                        format!("sheet uses missing texture {}", sheet.texture),
+                    );
+                }
+
+                Some(sheet)
+            }
+            Err(e) => {
+                self.problem(path, format!("invalid sheet: {}", e));
+                None
+            }
+        };
+
+        self.sheets
+            .insert(normalize(path).to_owned(), sheet.clone());
+        sheet
+    }
+
+    /// Checks that a texture decodes, returning its size.
+    fn texture(&mut self, path: &str) -> Option<(u32, u32)> {
+        if let Some(size) = self.textures.get(normalize(path)) {
+            return *size;
+        }
+
+        let result = self.vfs.read(path).and_then(|(bytes, _)| {
+            let image = image::io::Reader::new(Cursor::new(bytes))
+                .with_guessed_format()?
+                .decode()?;
+            Ok((image.width(), image.height()))
+        });
+
+        let size = match result {
+            Ok(size) => Some(size),
+            Err(e) => {
+                self.problem(path, format!("cannot load texture: {}", e));
+                None
+            }
+        };
+
+        self.textures.insert(normalize(path).to_owned(), size);
+        size
+    }
+
+    /// Compiles a script, returning the states it changes to.
+    fn script(&mut self, path: &str) -> Vec<String> {
+        if let Some(targets) = self.scripts.get(normalize(path)) {
+            return targets.clone().unwrap_or_default();
+        }
+
+        let compiled = self.vfs.read(path).and_then(|(bytes, _)| {
+            let source = String::from_utf8(bytes)?;
+            self.engine.compile(&source)?;
+            Ok(change_targets(&source))
+        });
+
+        let targets = match compiled {
+            Ok(targets) => Some(targets),
+            Err(e) => {
+                self.problem(path, format!("cannot compile script: {}", e));
+                None
+            }
+        };
+
+        self.scripts
+            .insert(normalize(path).to_owned(), targets.clone());
+        targets.unwrap_or_default()
+    }
+}
+
+/// Finds the states a script changes to with `state.change("...")`.
+///
+/// Only string literals can be checked. Changes to computed names are
+/// skipped.
+fn change_targets(source: &str) -> Vec<String> {
+    let mut targets = Vec::new();
+
+    for (i, _) in source.match_indices(".change(") {
+        let rest = source[i + ".change(".len()..].trim_start();
+
+        if let Some(rest) = rest.strip_prefix('"') {
+            if let Some(end) = rest.find('"') {
+                let target = &rest[..end];
+
+                if !targets.iter().any(|t| t == target) {
+                    targets.push(target.to_owned());
+                }
+            }
+        }
+    }
+
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets() {
+        let source = r#"
+            fn onupdate() {
+                if inputs.direction == D6 {
+                    state.change("forward")
+                } else if inputs.direction == D4 {
+                    state.change( "backward" );
+                } else {
+                    state.change("forward");
+                    state.change(next_state);
+                }
+            }
+        "#;
+
+        assert_eq!(change_targets(source), ["forward", "backward"]);
+    }
+
+    #[test]
+    fn broken() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let missing = fixtures.join("missing");
+
+        let problems =
+            validate_bundles(&[fixtures.join("broken"), missing.clone()], &Engine::new());
+        let found = |path: &str, message: &str| {
+            problems
+                .iter()
+                .any(|p| p.path == path && p.message.contains(message))
+        };
+
+        // the missing bundle doesn't stop the broken one from being validated
+        assert!(found(&missing.display().to_string(), "cannot mount bundle"));
+
+        assert!(found("characters/broken.ron", "no `idle` state"));
+        assert!(found(
+            "characters/broken.ron",
+            "state `stand` uses missing texture /img/missing.png"
+        ));
+        assert!(found("scripts/stand.rhai", "changes to state `run`"));
+        assert!(found("scripts/walk.rhai", "cannot compile script"));
+        assert!(found("characters/../escape.ron", "leads out of the bundle"));
+        assert_eq!(problems.len(), 6, "{:#?}", problems);
+    }
+}
@@ -0,0 +1,227 @@
+//! The battle camera.
+//!
+//! The camera follows the players around the stage. It moves on frames of
+//! gameplay, not frames drawn, and only ever looks at the arena, so it is
+//! rolled back and replayed with everything else and a replay is framed
+//! exactly like the match was.
+//!
+//! Nothing about the camera depends on the window either. It frames the
+//! players for a [`REFERENCE_ASPECT_RATIO`] screen; wider screens see more
+//! of the stage to each side.
+
+use glam::f32::{Affine2, Vec2};
+
+/// The aspect ratio, height over width, the camera frames the players for.
+pub const REFERENCE_ASPECT_RATIO: f32 = 9. / 16.;
+
+/// The least height the camera shows, in world units.
+pub const MIN_HEIGHT: f32 = 2.5;
+
+/// The most height the camera shows, in world units.
+pub const MAX_HEIGHT: f32 = 6.0;
+
+/// How much of the way to its target the camera moves each frame.
+pub const SMOOTHING: f32 = 0.2;
+
+/// How far below the lowest player the bottom of the view is.
+const FLOOR_MARGIN: f32 = 0.25;
+
+/// The space kept to either side of a player, from their origin.
+const PLAYER_MARGIN: f32 = 0.4;
+
+/// The space kept above a player, from their origin.
+const PLAYER_HEIGHT: f32 = 2.0;
+
+/// A camera.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Camera {
+    center: Vec2,
+    height: f32,
+    // how far the stage reaches to either side of its center
+    bounds: f32,
+    shake: Option<Shake>,
+    zoom: Option<Zoom>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Shake {
+    magnitude: f32,
+    frames: u32,
+    left: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Zoom {
+    center: Vec2,
+    height: f32,
+    left: u32,
+}
+
+impl Camera {
+    /// Creates a camera framing players at `positions`, on a stage that
+    /// reaches `bounds` units to either side of its center.
+    pub fn new(positions: [Vec2; 2], bounds: f32) -> Camera {
+        let mut camera = Camera {
+            center: Vec2::ZERO,
+            height: MIN_HEIGHT,
+            bounds,
+            shake: None,
+            zoom: None,
+        };
+
+        let (center, height) = camera.target(positions);
+        camera.center = center;
+        camera.height = height;
+        camera
+    }
+
+    /// Moves the camera toward framing players at `positions`.
+    pub fn update(&mut self, positions: [Vec2; 2]) {
+        if let Some(zoom) = &mut self.zoom {
+            zoom.left -= 1;
+        }
+
+        let (center, height) = match self.zoom {
+            Some(zoom) => (zoom.center, zoom.height),
+            None => self.target(positions),
+        };
+
+        self.center += (center - self.center) * SMOOTHING;
+        self.height += (height - self.height) * SMOOTHING;
+
+        if let Some(shake) = &mut self.shake {
+            shake.left -= 1;
+        }
+
+        self.zoom = self.zoom.filter(|z| z.left > 0);
+        self.shake = self.shake.filter(|s| s.left > 0);
+    }
+
+    /// Shakes the camera for `frames` frames, up to `magnitude` units away
+    /// from where it would be.
+    ///
+    /// The shake dies down over time. A new shake replaces the last one.
+    pub fn shake(&mut self, magnitude: f32, frames: u32) {
+        self.shake = (frames > 0).then_some(Shake {
+            magnitude,
+            frames,
+            left: frames,
+        });
+    }
+
+    /// Zooms in on `center` for `frames` frames, showing `height` units, like
+    /// for a super freeze.
+    ///
+    /// The camera eases in and out of the zoom like it would any other move,
+    /// and ignores the players until the zoom is over.
+    pub fn zoom(&mut self, center: Vec2, height: f32, frames: u32) {
+        self.zoom = (frames > 0).then_some(Zoom {
+            center,
+            height: height.clamp(MIN_HEIGHT / 2., MAX_HEIGHT),
+            left: frames,
+        });
+    }
+
+    /// The center of the view, without shake.
+    pub fn center(&self) -> Vec2 {
+        self.center
+    }
+
+    /// The height of the view, in world units.
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    /// The world transform of the camera, for a screen `1` unit tall.
+    pub fn transform(&self) -> Affine2 {
+        let scale = 1. / self.height;
+
+        Affine2::from_scale(Vec2::new(scale, scale))
+            * Affine2::from_translation(-(self.center + self.shake_offset()))
+    }
+
+    /// Where the camera would frame players at `positions`.
+    fn target(&self, positions: [Vec2; 2]) -> (Vec2, f32) {
+        let [p1, p2] = positions;
+        let min = p1.min(p2) - Vec2::new(PLAYER_MARGIN, 0.);
+        let max = p1.max(p2) + Vec2::new(PLAYER_MARGIN, PLAYER_HEIGHT);
+
+        let height = ((max.x - min.x) * REFERENCE_ASPECT_RATIO)
+            .max(max.y - min.y + FLOOR_MARGIN)
+            .clamp(MIN_HEIGHT, MAX_HEIGHT);
+
+        // keep the floor in view, unless someone jumped out of it
+        let y = (min.y - FLOOR_MARGIN + height / 2.).max(max.y - height / 2.);
+
+        // don't look past the edges of the stage
+        let half_width = height / REFERENCE_ASPECT_RATIO / 2.;
+        let reach = (self.bounds - half_width).max(0.);
+        let x = ((min.x + max.x) / 2.).clamp(-reach, reach);
+
+        (Vec2::new(x, y), height)
+    }
+
+    /// How far the shake moves the camera this frame.
+    fn shake_offset(&self) -> Vec2 {
+        let shake = match &self.shake {
+            Some(shake) => shake,
+            None => return Vec2::ZERO,
+        };
+
+        // a fixed jitter, so the shake is the same every time it's replayed
+        let strength = shake.magnitude * shake.left as f32 / shake.frames as f32;
+        let x = if shake.left % 2 == 0 { 1. } else { -1. };
+        let y = [0., 1., -1.][shake.left as usize % 3];
+
+        Vec2::new(x, y * 0.5) * strength
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow() {
+        let ground = [Vec2::new(-1., 0.), Vec2::new(1., 0.)];
+        let mut camera = Camera::new(ground, 3.);
+
+        // both players fit at the closest zoom, with the floor near the bottom
+        assert_eq!(camera.center(), Vec2::new(0., 1.));
+        assert_eq!(camera.height(), MIN_HEIGHT);
+
+        // the camera eases up after a jump, and zooms out to keep both
+        // players in view
+        let jump = [Vec2::new(-1., 0.), Vec2::new(1., 3.)];
+        camera.update(jump);
+        assert!(camera.center().y > 1. && camera.height() > MIN_HEIGHT);
+
+        for _ in 0..100 {
+            camera.update(jump);
+        }
+        assert!(camera.center().y + camera.height() / 2. >= 3. + PLAYER_HEIGHT - 1e-3);
+
+        // near the wall, the camera stops at the edge of the stage
+        let wall = [Vec2::new(2.5, 0.), Vec2::new(2.8, 0.)];
+        for _ in 0..100 {
+            camera.update(wall);
+        }
+        let half_width = camera.height() / REFERENCE_ASPECT_RATIO / 2.;
+        assert!((camera.center().x + half_width - 3.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn shake() {
+        let ground = [Vec2::new(-1., 0.), Vec2::new(1., 0.)];
+        let mut camera = Camera::new(ground, 3.);
+        let rest = camera.transform();
+
+        camera.shake(0.2, 4);
+        assert_ne!(camera.transform(), rest);
+
+        for _ in 0..4 {
+            camera.update(ground);
+        }
+        assert_eq!(camera.transform(), rest);
+    }
+}
@@ -5,7 +5,9 @@ use std::ops::Deref;
 use std::sync::Arc;
 
 use crate::battle::script::AST;
-use crate::render::Sprite;
+use crate::render::{Sprite, Texture};
+
+use bftd_lib::Rect;
 
 /// A cheaply-cloneable key for a finite-state machine entry.
 pub type Key = Arc<str>;
@@ -39,6 +41,8 @@ pub type Key = Arc<str>;
 #[derive(Clone, Debug)]
 pub struct Fsm {
     states: Arc<HashMap<Key, State>>,
+    palettes: Arc<[Texture]>,
+    digest: u64,
 }
 
 impl Fsm {
@@ -54,8 +58,44 @@ impl Fsm {
 
         Fsm {
             states: Arc::new(states),
+            palettes: Arc::new([]),
+            digest: 0,
+        }
+    }
+
+    /// Attaches the colour-remap textures of the alternate palettes of the
+    /// `Fsm`.
+    pub fn with_palettes(self, palettes: Vec<Texture>) -> Fsm {
+        Fsm {
+            palettes: palettes.into(),
+            ..self
         }
     }
+
+    /// The number of palettes the `Fsm` can be drawn in, including the
+    /// colours of its sprites.
+    pub fn palette_count(&self) -> usize {
+        self.palettes.len() + 1
+    }
+
+    /// The colour-remap texture of a palette.
+    ///
+    /// Palette `0` is the colours of the sprites, and has none.
+    pub fn palette(&self, palette: usize) -> Option<&Texture> {
+        palette.checked_sub(1).and_then(|i| self.palettes.get(i))
+    }
+
+    /// Attaches a digest of the assets the `Fsm` was built from.
+    pub fn with_digest(self, digest: u64) -> Fsm {
+        Fsm { digest, ..self }
+    }
+
+    /// The digest of the assets the `Fsm` was built from.
+    ///
+    /// This is `0` for an `Fsm` that wasn't loaded from assets.
+    pub fn digest(&self) -> u64 {
+        self.digest
+    }
 }
 
 impl Deref for Fsm {
@@ -96,8 +136,16 @@ impl State {
 }
 
 /// A single frame in a [`State`].
-#[derive(Clone, Debug)]
+///
+/// Boxes are relative to the origin of the entity, facing right.
+#[derive(Clone, Debug, Default)]
 pub struct Frame {
     /// The sprite to display for this frame.
     pub sprite: Option<Sprite>,
+    /// The boxes that hit other entities.
+    pub hitboxes: Vec<Rect>,
+    /// The boxes other entities can hit.
+    pub hurtboxes: Vec<Rect>,
+    /// The box that keeps entities from walking through each other.
+    pub pushbox: Option<Rect>,
 }
@@ -0,0 +1,372 @@
+//! Pre-match handshakes between [`NetBattle`](super::NetBattle) peers.
+//!
+//! A rollback session only works if both peers simulate the exact same game.
+//! Before a match starts, each peer sends the other a [`Handshake`]
+//! describing the game it built: the bundles it loaded, a digest of the
+//! roster and stage of its [`Series`](super::Series), the characters,
+//! palettes and stage picked and which side it plays on. If anything disagrees, both
+//! peers bail out with an error instead of desyncing silently.
+//!
+//! Spectators are sent the host's handshake too, and check it the same way
+//! with [`Handshake::verify_game`], except for the side.
+//!
+//! Once the handshakes are through, the peers ping each other for a moment to
+//! measure the round-trip time, which the [`NetBattle`](super::NetBattle) can
+//! pick its frame delay from.
+
+use bftd_lib::Metadata;
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Error;
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a peer's handshake by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a handshake is resent while waiting on the peer.
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many times the final handshake is sent, in case some are lost.
+const FINAL_SENDS: usize = 3;
+
+/// How long the round-trip time is measured for.
+const PING_DURATION: Duration = Duration::from_millis(500);
+
+/// How often a ping is sent while measuring the round-trip time.
+const PING_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A description of a peer's game.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Handshake {
+    /// The bundles loaded, in order.
+    pub bundles: Vec<Metadata>,
+    /// The digest of the peer's [`Series`](super::Series), covering every
+    /// character in the roster and the stage. See
+    /// [`Series::digest`](super::Series::digest).
+    pub digest: u64,
+    /// The characters picked, left then right.
+    pub characters: [String; 2],
+    /// The stage picked, if any.
+    pub stage: Option<String>,
+    /// The palettes picked, left then right.
+    pub palettes: [usize; 2],
+    /// The side the peer plays on. `0` is left, `1` is right.
+    pub side: usize,
+}
+
+impl Handshake {
+    /// Checks that a remote peer's handshake agrees with this one, and that
+    /// the peers play on different sides.
+    pub fn verify(&self, remote: &Handshake) -> Result<(), Error> {
+        self.verify_game(remote)?;
+
+        if self.side == remote.side {
+            bail!("side mismatch: both peers want to play side {}", self.side);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a remote's handshake describes the same game as this one,
+    /// whichever side either plays on.
+    pub fn verify_game(&self, remote: &Handshake) -> Result<(), Error> {
+        if self.bundles != remote.bundles {
+            bail!(
+                "bundle mismatch: local has [{}], remote has [{}]",
+                list(&self.bundles),
+                list(&remote.bundles)
+            );
+        }
+
+        if self.characters != remote.characters {
+            bail!(
+                "character mismatch: local picked {} vs {}, remote picked {} vs {}",
+                self.characters[0],
+                self.characters[1],
+                remote.characters[0],
+                remote.characters[1]
+            );
+        }
+
+        if self.stage != remote.stage {
+            bail!(
+                "stage mismatch: local picked {}, remote picked {}",
+                self.stage.as_deref().unwrap_or("no stage"),
+                remote.stage.as_deref().unwrap_or("no stage")
+            );
+        }
+
+        if self.palettes != remote.palettes {
+            bail!(
+                "palette mismatch: local picked {:?}, remote picked {:?}",
+                self.palettes,
+                remote.palettes
+            );
+        }
+
+        if self.digest != remote.digest {
+            bail!(
+                "content mismatch: local roster and stage have digest {:016x}, remote has {:016x}; \
+                 the bundles have the same version but different contents",
+                self.digest,
+                remote.digest
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// A remote peer, after a successful [`exchange`].
+#[derive(Clone, Debug)]
+pub struct Remote {
+    /// The peer's handshake.
+    pub handshake: Handshake,
+    /// The median round-trip time to the peer, if any pings came back.
+    pub rtt: Option<Duration>,
+}
+
+/// Exchanges handshakes with a remote peer.
+///
+/// This blocks until the peer's handshake has been received and verified, or
+/// until `timeout` passes, then spends a moment measuring the round-trip time.
+/// The socket is closed afterwards, so the same address can be bound again by
+/// the [`NetBattle`](super::NetBattle).
+pub fn exchange(
+    bind_addrs: impl ToSocketAddrs,
+    remote: SocketAddr,
+    local: &Handshake,
+    timeout: Duration,
+) -> Result<Remote, Error> {
+    let socket = UdpSocket::bind(bind_addrs)?;
+    socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+
+    let start = Instant::now();
+    let mut received: Option<Handshake> = None;
+    let mut buf = [0u8; 4096];
+
+    info!("waiting for handshake from {}...", remote);
+
+    loop {
+        if start.elapsed() > timeout {
+            bail!("timed out waiting for a handshake from {}", remote);
+        }
+
+        let packet = Packet::Hello {
+            handshake: local.clone(),
+            received: received.is_some(),
+        };
+        send(&socket, &packet, remote)?;
+
+        match recv(&socket, &mut buf, remote)? {
+            Some(Packet::Hello {
+                handshake,
+                received: done,
+            }) => {
+                received = Some(handshake);
+
+                // the peer has our handshake and we have theirs
+                if done {
+                    break;
+                }
+            }
+            // the peer is already measuring
+            Some(Packet::Ping { stamp }) => send(&socket, &Packet::Pong { stamp }, remote)?,
+            Some(Packet::Pong { .. }) | None => (),
+        }
+    }
+
+    // let the peer know we have theirs, too
+    let hello = Packet::Hello {
+        handshake: local.clone(),
+        received: true,
+    };
+    for _ in 0..FINAL_SENDS {
+        send(&socket, &hello, remote)?;
+    }
+
+    let remote_handshake = received.unwrap();
+    local.verify(&remote_handshake)?;
+
+    let rtt = measure_rtt(&socket, remote, &hello)?;
+
+    match rtt {
+        Some(rtt) => info!("round-trip time to {} is {}ms", remote, rtt.as_millis()),
+        None => warn!("could not measure the round-trip time to {}", remote),
+    }
+
+    Ok(Remote {
+        handshake: remote_handshake,
+        rtt,
+    })
+}
+
+/// Pings the peer for [`PING_DURATION`], returning the median round-trip time.
+fn measure_rtt(
+    socket: &UdpSocket,
+    remote: SocketAddr,
+    hello: &Packet,
+) -> Result<Option<Duration>, Error> {
+    socket.set_read_timeout(Some(PING_INTERVAL))?;
+
+    let start = Instant::now();
+    let mut samples = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    while start.elapsed() < PING_DURATION {
+        let stamp = start.elapsed().as_micros() as u64;
+        send(socket, &Packet::Ping { stamp }, remote)?;
+
+        match recv(socket, &mut buf, remote)? {
+            Some(Packet::Ping { stamp }) => send(socket, &Packet::Pong { stamp }, remote)?,
+            Some(Packet::Pong { stamp }) => {
+                let now = start.elapsed().as_micros() as u64;
+                samples.push(Duration::from_micros(now.saturating_sub(stamp)));
+            }
+            // the peer missed our final handshakes
+            Some(Packet::Hello { .. }) => send(socket, hello, remote)?,
+            None => (),
+        }
+    }
+
+    samples.sort();
+    Ok(samples.get(samples.len() / 2).copied())
+}
+
+#[derive(Deserialize, Serialize)]
+enum Packet {
+    Hello {
+        handshake: Handshake,
+        // if the sender has received the receiver's handshake
+        received: bool,
+    },
+    // stamps are microseconds since the sender started measuring
+    Ping {
+        stamp: u64,
+    },
+    Pong {
+        stamp: u64,
+    },
+}
+
+fn send(socket: &UdpSocket, packet: &Packet, remote: SocketAddr) -> Result<(), Error> {
+    let packet = ron::to_string(packet)?;
+
+    match socket.send_to(packet.as_bytes(), remote) {
+        Ok(_) => Ok(()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Receives a packet from `remote`, if one arrives before the read timeout.
+fn recv(socket: &UdpSocket, buf: &mut [u8], remote: SocketAddr) -> Result<Option<Packet>, Error> {
+    match socket.recv_from(buf) {
+        Ok((len, addr)) if addr == remote => match ron::de::from_bytes(&buf[..len]) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(e) => {
+                warn!("bad handshake from {}: {}", addr, e);
+                Ok(None)
+            }
+        },
+        Ok((_, addr)) => {
+            warn!("ignoring handshake from unknown peer {}", addr);
+            Ok(None)
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+        // the peer isn't up yet
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn list(bundles: &[Metadata]) -> String {
+    bundles
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn handshake(side: usize) -> Handshake {
+        Handshake {
+            bundles: vec![Metadata::new("Core", semver::Version::new(0, 1, 0))],
+            digest: 0xdead_beef,
+            characters: ["grand_dad".into(), "hh".into()],
+            stage: Some("/stages/dojo.ron".into()),
+            palettes: [0, 1],
+            side,
+        }
+    }
+
+    #[test]
+    fn verify() {
+        assert!(handshake(0).verify(&handshake(1)).is_ok());
+        assert!(handshake(0).verify(&handshake(0)).is_err());
+        assert!(handshake(0).verify_game(&handshake(0)).is_ok());
+
+        let mut remote = handshake(1);
+        remote.bundles[0].version = semver::Version::new(0, 2, 0);
+        assert!(handshake(0).verify(&remote).is_err());
+
+        let mut remote = handshake(1);
+        remote.digest += 1;
+        assert!(handshake(0).verify(&remote).is_err());
+
+        let mut remote = handshake(1);
+        remote.characters.swap(0, 1);
+        assert!(handshake(0).verify(&remote).is_err());
+
+        let mut remote = handshake(1);
+        remote.stage = None;
+        assert!(handshake(0).verify(&remote).is_err());
+
+        let mut remote = handshake(1);
+        remote.palettes = [1, 0];
+        assert!(handshake(0).verify(&remote).is_err());
+    }
+
+    #[test]
+    fn exchange_loopback() {
+        // reserve two ports
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        drop((a, b));
+
+        let timeout = Duration::from_secs(5);
+        let peer = thread::spawn(move || exchange(b_addr, a_addr, &handshake(1), timeout));
+
+        let remote = exchange(a_addr, b_addr, &handshake(0), timeout).unwrap();
+        assert_eq!(remote.handshake, handshake(1));
+        assert!(remote.rtt.is_some());
+
+        let peer = peer.join().unwrap().unwrap();
+        assert_eq!(peer.handshake, handshake(0));
+        assert!(peer.rtt.is_some());
+    }
+}
@@ -0,0 +1,249 @@
+//! The in-battle HUD.
+//!
+//! The HUD is drawn over the arena in screen space, so it stays put however
+//! the camera moves. What goes where is read from a [`Layout`] in the bundle,
+//! at [`LAYOUT_PATH`], so a mod can theme the HUD by shipping its own.
+
+use super::{Series, MAX_HEALTH, MAX_METER};
+
+use crate::render::{Renderer, Texture};
+
+use bftd_lib::hud::{Bar, Layout, Number};
+use bftd_lib::sheet::Region;
+use bftd_lib::Rect;
+use glam::f32::{Affine2, Vec2};
+
+/// The path the layout of the HUD is loaded from.
+pub const LAYOUT_PATH: &str = "/hud.ron";
+
+/// The layer the HUD is drawn on, over the arena.
+///
+/// Numbers are drawn a layer higher, so bars never hide them.
+pub const HUD_LAYER: i32 = 100;
+
+/// A HUD, ready to draw.
+pub struct Hud {
+    layout: Layout,
+    digits: Texture,
+    // the source rectangle of each digit, and its width over its height
+    glyphs: Vec<(Rect, f32)>,
+}
+
+impl Hud {
+    /// Creates a new `Hud`.
+    ///
+    /// `digits` is the texture of the digits sheet of the layout, and
+    /// `regions` are the regions of `"0"` to `"9"` in it.
+    pub fn new(layout: Layout, digits: Texture, regions: [Region; 10]) -> Hud {
+        let glyphs = regions
+            .iter()
+            .map(|region| {
+                let src = region.normalize(digits.width(), digits.height());
+                (src, region.width as f32 / region.height.max(1) as f32)
+            })
+            .collect();
+
+        Hud {
+            layout,
+            digits,
+            glyphs,
+        }
+    }
+
+    /// The layout of the HUD.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Draws the HUD of a series.
+    ///
+    /// The world transform and layer of the renderer are restored after.
+    pub fn draw(&self, series: &Series, cx: &mut Renderer) {
+        let (world, layer) = (cx.transform(), cx.layer());
+        cx.set_transform(Affine2::IDENTITY);
+
+        let arena = series.arena();
+        let layout = &self.layout;
+
+        for (side, player) in arena.players().into_iter().enumerate() {
+            let state = player.state();
+            let mirrored = side == 1;
+
+            cx.set_layer(HUD_LAYER);
+            bar(
+                cx,
+                &layout.health,
+                state.health as f32 / MAX_HEALTH as f32,
+                mirrored,
+            );
+            bar(
+                cx,
+                &layout.meter,
+                state.meter as f32 / MAX_METER as f32,
+                mirrored,
+            );
+
+            cx.set_layer(HUD_LAYER + 1);
+            self.number(cx, &layout.wins, series.wins()[side], mirrored);
+
+            let combo = arena.combos()[side];
+            if combo.hits >= 2 {
+                self.number(cx, &layout.combo.hits, combo.hits, mirrored);
+                self.number(cx, &layout.combo.damage, combo.damage as u32, mirrored);
+            }
+        }
+
+        self.number(cx, &layout.timer, arena.timer(), false);
+        self.number(cx, &layout.round, series.round(), false);
+
+        cx.set_transform(world);
+        cx.set_layer(layer);
+    }
+
+    /// Draws a number, centered on its position.
+    fn number(&self, cx: &mut Renderer, number: &Number, value: u32, mirrored: bool) {
+        let glyphs = value
+            .to_string()
+            .bytes()
+            .map(|b| &self.glyphs[(b - b'0') as usize])
+            .collect::<Vec<_>>();
+
+        let width = glyphs
+            .iter()
+            .map(|(_, aspect)| aspect * number.height)
+            .sum::<f32>();
+
+        let mut pos = number.pos;
+        if mirrored {
+            pos.x = -pos.x;
+        }
+
+        let mut x = pos.x - width / 2.;
+        for (src, aspect) in glyphs {
+            let glyph = Vec2::new(aspect * number.height, number.height);
+            let transform = Affine2::from_translation(Vec2::new(x + glyph.x / 2., pos.y))
+                * Affine2::from_scale(glyph);
+
+            cx.draw_texture(&self.digits, src.clone(), transform, number.color.into());
+            x += glyph.x;
+        }
+    }
+}
+
+/// Draws a bar filled up to `fill`, from `0` to `1`.
+fn bar(cx: &mut Renderer, bar: &Bar, fill: f32, mirrored: bool) {
+    let transform = if mirrored {
+        Affine2::from_scale(Vec2::new(-1., 1.))
+    } else {
+        Affine2::IDENTITY
+    };
+
+    let rect = &bar.rect;
+    let filled = Rect::new_wh(
+        rect.left(),
+        rect.bottom(),
+        rect.width() * fill.clamp(0., 1.),
+        rect.height(),
+    );
+
+    cx.fill_rect(rect, transform, bar.back.into());
+    cx.fill_rect(&filled, transform, bar.fill.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::battle::fsm::{self, Fsm, Key};
+    use crate::battle::script::Engine;
+    use crate::render::Color;
+
+    use bftd_lib::hud::Combo;
+
+    fn number(x: f32, y: f32) -> Number {
+        Number {
+            pos: Vec2::new(x, y),
+            height: 0.05,
+            color: [1., 1., 1., 1.],
+        }
+    }
+
+    fn bar(y: f32) -> Bar {
+        Bar {
+            rect: Rect::new(-0.8, y, -0.1, y + 0.04),
+            back: [0., 0., 0., 1.],
+            fill: [1., 0., 0., 1.],
+        }
+    }
+
+    #[test]
+    fn draw() {
+        let layout = Layout {
+            digits: "/hud/digits.ron".to_owned(),
+            health: bar(0.4),
+            meter: bar(-0.45),
+            timer: number(0., 0.42),
+            round: number(0., 0.36),
+            wins: number(-0.1, 0.36),
+            combo: Combo {
+                hits: number(-0.6, 0.2),
+                damage: number(-0.6, 0.15),
+            },
+        };
+
+        // ten 8x16 digits in a row
+        let regions = std::array::from_fn(|i| Region {
+            x: i as u32 * 8,
+            y: 0,
+            width: 8,
+            height: 16,
+        });
+        let hud = Hud::new(layout, Texture::headless(80, 16), regions);
+
+        let idle = Fsm::new([fsm::State {
+            name: Key::from("idle"),
+            frames: vec![fsm::Frame::default()],
+            script: None,
+        }]);
+        let series = Series::new(&Engine::new(), vec![idle], [0, 0]).unwrap();
+
+        // a camera far away doesn't move the HUD
+        let mut renderer = Renderer::new(9. / 16.);
+        renderer.set_transform(Affine2::from_scale(Vec2::new(0.01, 0.01)));
+        hud.draw(&series, &mut renderer);
+
+        assert_eq!(
+            renderer.transform(),
+            Affine2::from_scale(Vec2::new(0.01, 0.01))
+        );
+        assert_eq!(renderer.layer(), 0);
+
+        // two bars and a win count for each player, then the timer and round
+        let commands = renderer.commands();
+        assert_eq!(commands.len(), 2 * (4 + 1) + 2 + 1);
+
+        let close = |a: Vec2, b: Vec2| a.abs_diff_eq(b, 1e-5);
+
+        // the full health bar of p2 is mirrored to the right
+        let (p1, p2) = (&commands[1], &commands[6]);
+        assert_eq!(p1.tint, Color::rgba(1., 0., 0., 1.));
+        assert_eq!(p1.layer, HUD_LAYER);
+        assert!(close(
+            p1.transform.translation,
+            Vec2::new(-0.45 * 9. / 8., 0.84)
+        ));
+        assert!(close(
+            p2.transform.translation,
+            Vec2::new(0.45 * 9. / 8., 0.84)
+        ));
+
+        // 99 seconds on the clock, in two digits
+        let timer = &commands[10..12];
+        assert!(timer.iter().all(|c| c.layer == HUD_LAYER + 1));
+        assert!(close(
+            timer[0].transform.translation,
+            Vec2::new(-0.0125 * 9. / 8., 0.84)
+        ));
+        assert_eq!(timer[1].src, Rect::new_wh(0.9, 0., 0.1, 1.));
+    }
+}
@@ -7,7 +7,10 @@ use crate::input::{
 use crate::render::Renderer;
 use crate::Context;
 
-use super::{Arena, FRAMES_PER_SECOND};
+use super::fsm::Fsm;
+use super::script::Engine;
+use super::series::Series;
+use super::{Battle, Outcome, FRAMES_PER_SECOND};
 
 use anyhow::Error;
 
@@ -15,7 +18,7 @@ use anyhow::Error;
 pub struct LocalBattle {
     p1: Player,
     p2: Player,
-    arena: Arena,
+    series: Series,
 }
 
 struct Player {
@@ -25,9 +28,9 @@ struct Player {
 
 impl LocalBattle {
     /// Creates a new `LocalBattle` with input handles.
-    pub fn new(arena: Arena, p1: Handle, p2: Handle) -> LocalBattle {
+    pub fn new(series: Series, p1: Handle, p2: Handle) -> LocalBattle {
         LocalBattle {
-            arena,
+            series,
             p1: Player {
                 id: p1,
                 inputs: Default::default(),
@@ -38,12 +41,14 @@ impl LocalBattle {
             },
         }
     }
+}
 
+impl Battle for LocalBattle {
     /// Polls an update for the `LocalBattle`.
     ///
     /// Because all of the input processing is done locally, this will wait
     /// until each frame is done processing.
-    pub fn update(&mut self, cx: &mut Context) -> Result<(), Error> {
+    fn update(&mut self, cx: &mut Context) -> Result<(), Error> {
         while cx.frame_limiter.should_update(FRAMES_PER_SECOND) {
             // sample from our players
             self.p1
@@ -53,15 +58,22 @@ impl LocalBattle {
                 .inputs
                 .push(cx.input.sample(self.p2.id).unwrap_or_default());
 
-            self.arena
+            self.series
                 .update(&cx.script, &self.p1.inputs, &self.p2.inputs)?;
         }
 
         Ok(())
     }
 
-    /// Draws the battle to a graphics context.
-    pub fn draw(&mut self, cx: &mut Renderer) -> Result<(), Error> {
-        self.arena.draw(cx)
+    fn draw(&mut self, cx: &mut Renderer) -> Result<(), Error> {
+        self.series.draw(cx)
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        self.series.outcome()
+    }
+
+    fn replace_character(&mut self, engine: &Engine, index: usize, fsm: Fsm) -> Result<(), Error> {
+        self.series.replace_character(engine, index, fsm)
     }
 }
@@ -17,28 +17,45 @@
 //!   The players' and projectiles' individual states are updated parallel to
 //!   each other. If there is a state change, this stage is repeated for that
 //!   entity.
+//! * **Clamp**  
+//!   Players are kept between the walls of the stage and above the floor.
 //! * **Flip**
 //!   Character players are flipped if they need to be.
 //! * **Collide**  
 //!   The game will attempt to process hitboxes, hurtboxes and collision boxes
 //!   and update their states accordingly.
 
+pub mod camera;
 pub mod fsm;
+pub mod handshake;
+pub mod hud;
 mod local;
 mod net;
 pub mod script;
+pub mod series;
+pub mod spectate;
+pub mod stage;
+pub mod stats;
+pub mod transport;
 
 pub use local::LocalBattle;
-pub use net::{NetBattle, NetPlayer};
+pub use net::{Connection, FrameDelay, NetBattle, NetPlayer};
+pub use series::Series;
+pub use spectate::SpectateBattle;
+pub use stage::Stage;
+pub use stats::NetStats;
 
 use crate::input::Buffer as InputBuffer;
-use crate::render::{Drawable, Renderer};
+use crate::render::{Color, Drawable, Renderer};
+use crate::Context;
 use fsm::{Fsm, Key};
 
+use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 
 use script::{Engine, Scope};
 
+use bftd_lib::Rect;
 use glam::f32::{Affine2, Vec2};
 
 use anyhow::Error;
@@ -46,15 +63,100 @@ use anyhow::Error;
 /// How many frames of logic are elapsed in a single second.
 pub const FRAMES_PER_SECOND: u64 = 60;
 
-/// The size of each stage in the game.
+/// How many frames a match lasts before time runs out.
+pub const MATCH_LENGTH: u32 = 99 * FRAMES_PER_SECOND as u32;
+
+/// The health each player starts a match with.
+pub const MAX_HEALTH: i32 = 1_000;
+
+/// The most meter a player can build up.
+pub const MAX_METER: i32 = 1_000;
+
+/// How many frames a combo is kept going without a hit before it drops.
+///
+/// There is no hitstun yet to tell when a combo is over, so for now any hit
+/// landed within this many frames of the last counts.
+pub const COMBO_TIMEOUT: u32 = 30;
+
+/// The size of the stage when none is picked.
 ///
 /// The origin of the stage is `0`. In the case of `10,000`, the stage would
 /// extend `5,000` units to the left and `5,000` units to the right.
 pub const STAGE_SIZE: f32 = 10_000.0;
 
+/// How close a player's origin can get to the edge of the stage.
+pub const WALL_MARGIN: f32 = 0.4;
+
 /// The maximum horizontal distance two players can be away from each other.
 pub const MAX_HORIZONTAL_DISTANCE: f32 = 3_000.0;
 
+/// The layer debug overlays are drawn on, over everything else.
+pub const DEBUG_LAYER: i32 = 1_000;
+
+/// The colour of hitboxes in the debug overlay.
+const HITBOX_COLOR: Color = Color::rgba(1., 0.1, 0.1, 0.4);
+/// The colour of hurtboxes in the debug overlay.
+const HURTBOX_COLOR: Color = Color::rgba(0.1, 1., 0.1, 0.3);
+/// The colour of pushboxes in the debug overlay.
+const PUSHBOX_COLOR: Color = Color::rgba(1., 1., 0.1, 0.3);
+
+/// A battle manager.
+///
+/// Battle managers feed inputs to an [`Arena`], whether they come from local
+/// devices, a remote peer or a battle being spectated.
+pub trait Battle {
+    /// Polls an update for the battle.
+    fn update(&mut self, cx: &mut Context) -> Result<(), Error>;
+
+    /// Draws the battle to a graphics context.
+    fn draw(&mut self, cx: &mut Renderer) -> Result<(), Error>;
+
+    /// The network statistics of the battle, if it is networked.
+    fn net_stats(&self) -> Option<&NetStats> {
+        None
+    }
+
+    /// How the battle ended, if it is over and can be let go of.
+    fn outcome(&self) -> Option<Outcome> {
+        None
+    }
+
+    /// A short message about the state of the battle, if there is anything
+    /// the players should know about.
+    fn status(&self) -> Option<String> {
+        None
+    }
+
+    /// Replaces the character at `index` in the roster with a freshly loaded
+    /// one, keeping the players where they are.
+    ///
+    /// Only battles that are entirely local can do this; swapping characters
+    /// under a remote peer would desync it.
+    fn replace_character(
+        &mut self,
+        _engine: &Engine,
+        _index: usize,
+        _fsm: Fsm,
+    ) -> Result<(), Error> {
+        Err(anyhow!("characters can only be replaced in a local battle"))
+    }
+}
+
+/// How a [`Battle`] ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The series finished, after a player chose to leave following a match.
+    Finished {
+        /// The number of matches each player won.
+        wins: [u32; 2],
+    },
+    /// The battle was left before the series finished, like when the host of
+    /// a spectated battle stops sending inputs.
+    Left,
+    /// The connection to the remote player was lost.
+    Disconnected,
+}
+
 /// A headless arena.
 ///
 /// This only handles the frame-by-frame logic of updating the match state, the
@@ -64,6 +166,9 @@ pub struct Arena {
     frame: u32,
     p1: Player,
     p2: Player,
+    combos: [Combo; 2],
+    camera: Camera,
+    stage: Stage,
 }
 
 impl Arena {
@@ -71,13 +176,44 @@ impl Arena {
     ///
     /// The initial state is always `"idle"`.
     pub fn new(engine: &Engine, p1: Fsm, p2: Fsm) -> Result<Arena, Error> {
+        let p1 = Player::new(engine, p1, State::initial_p1())?;
+        let p2 = Player::new(engine, p2, State::initial_p2())?;
+        let stage = Stage::void();
+        let camera = Camera::new([p1.pos(), p2.pos()], stage.width() / 2.);
+
         Ok(Arena {
             frame: 0,
-            p1: Player::new(engine, p1, State::initial_p1())?,
-            p2: Player::new(engine, p2, State::initial_p2())?,
+            p1,
+            p2,
+            combos: [Combo::default(); 2],
+            camera,
+            stage,
         })
     }
 
+    /// Fights the battle on `stage`.
+    ///
+    /// This should be done before the first frame is processed.
+    pub fn with_stage(self, stage: Stage) -> Arena {
+        let camera = Camera::new([self.p1.pos(), self.p2.pos()], stage.width() / 2.);
+
+        Arena {
+            camera,
+            stage,
+            ..self
+        }
+    }
+
+    /// Draws the players in the given palettes, left then right.
+    ///
+    /// Palettes only change how the players look, so they can be set any
+    /// time.
+    pub fn with_palettes(mut self, palettes: [usize; 2]) -> Arena {
+        self.p1.set_palette(palettes[0]);
+        self.p2.set_palette(palettes[1]);
+        self
+    }
+
     /// Processes the next frame of gameplay using the inputs provided for each
     /// player.
     pub fn update(
@@ -88,10 +224,23 @@ impl Arena {
     ) -> Result<(), Error> {
         self.frame += 1;
 
+        let health = [self.p1.state.health, self.p2.state.health];
+
         // first, update each player's individual state
         self.p1.update(engine, p1)?;
         self.p2.update(engine, p2)?;
 
+        // whatever health a player lost was dealt by the other
+        self.combos[0].update(health[1] - self.p2.state.health);
+        self.combos[1].update(health[0] - self.p1.state.health);
+
+        // keep everyone on the stage
+        let wall = self.stage.wall();
+        for player in [&mut self.p1, &mut self.p2] {
+            let pos = &mut player.state_mut().pos;
+            *pos = Vec2::new(pos.x.clamp(-wall, wall), pos.y.max(0.));
+        }
+
         // do flip post-processing after update
         if self.p1.pos().x < self.p2.pos().x {
             self.p1.state_mut().flipped = false;
@@ -101,35 +250,124 @@ impl Arena {
             self.p2.state_mut().flipped = false;
         }
 
+        self.camera.update([self.p1.pos(), self.p2.pos()]);
+
         Ok(())
     }
 
     /// Draws the battle to a graphics context.
     pub fn draw(&self, cx: &mut Renderer) -> Result<(), Error> {
-        let aspect_ratio = 1. / cx.aspect_ratio();
+        cx.set_transform(self.camera.transform());
 
-        let min = self.p1.state.pos.min(self.p2.state.pos) - Vec2::new(0.4, 0.);
-        let max = self.p1.state.pos.max(self.p2.state.pos) + Vec2::new(0.4, 2.0);
+        self.stage.draw(&self.camera, cx);
 
-        let center = (min + max) / 2.;
+        self.p2.draw(cx)?;
+        self.p1.draw(cx)?;
 
-        let scale_x = aspect_ratio / (max.x - min.x);
-        let scale_y = 1. / max.y - min.y;
+        if cx.debug() {
+            let layer = cx.layer();
+            cx.set_layer(DEBUG_LAYER);
 
-        let scale = scale_x.min(scale_y).min(0.4);
+            self.p2.draw_debug(cx)?;
+            self.p1.draw_debug(cx)?;
 
-        cx.set_transform(
-            Affine2::from_scale(Vec2::new(scale, scale)) * Affine2::from_translation(-center),
-        );
+            cx.set_layer(layer);
+        }
 
-        self.p2.draw(cx)?;
-        self.p1.draw(cx)
+        Ok(())
     }
 
     /// The amount of frames that have passed in the battle.
     pub fn frame(&self) -> u32 {
         self.frame
     }
+
+    /// Swaps the [`Fsm`] of the player on `side`, keeping their position.
+    ///
+    /// `0` is left, `1` is right. See [`Player::replace`].
+    pub fn replace(&mut self, engine: &Engine, side: usize, fsm: Fsm) -> Result<(), Error> {
+        match side {
+            0 => self.p1.replace(engine, fsm),
+            1 => self.p2.replace(engine, fsm),
+            _ => Err(anyhow!("no player on side {}", side)),
+        }
+    }
+
+    /// The players, left to right.
+    pub fn players(&self) -> [&Player; 2] {
+        [&self.p1, &self.p2]
+    }
+
+    /// The stage the battle is fought on.
+    pub fn stage(&self) -> &Stage {
+        &self.stage
+    }
+
+    /// The camera following the players.
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// A mutable reference to the camera, to shake or zoom it.
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+
+    /// The combo each player is landing on the other.
+    pub fn combos(&self) -> [Combo; 2] {
+        self.combos
+    }
+
+    /// The seconds left until time runs out, rounded up.
+    pub fn timer(&self) -> u32 {
+        let fps = FRAMES_PER_SECOND as u32;
+
+        (MATCH_LENGTH.saturating_sub(self.frame) + fps - 1) / fps
+    }
+
+    /// Checks if the match is over, either because time ran out or because a
+    /// player was knocked out.
+    pub fn is_over(&self) -> bool {
+        self.frame >= MATCH_LENGTH || self.p1.state.health <= 0 || self.p2.state.health <= 0
+    }
+
+    /// The side of the player with the most health left, or `None` if it is a
+    /// draw.
+    pub fn winner(&self) -> Option<usize> {
+        match self.p1.state.health.cmp(&self.p2.state.health) {
+            Ordering::Greater => Some(0),
+            Ordering::Less => Some(1),
+            Ordering::Equal => None,
+        }
+    }
+}
+
+/// A combo one player is landing on the other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Combo {
+    /// The number of hits landed.
+    pub hits: u32,
+    /// The damage dealt.
+    pub damage: i32,
+    // frames since the last hit
+    idle: u32,
+}
+
+impl Combo {
+    /// Counts a frame in which `damage` was dealt.
+    fn update(&mut self, damage: i32) {
+        if damage > 0 {
+            self.hits += 1;
+            self.damage += damage;
+            self.idle = 0;
+        } else if self.hits > 0 {
+            self.idle += 1;
+
+            if self.idle > COMBO_TIMEOUT {
+                *self = Combo::default();
+            }
+        }
+    }
 }
 
 /// One of two players in a battle.
@@ -137,6 +375,7 @@ pub struct Player {
     fsm: Fsm,
     state: State,
     scope: Scope<'static>,
+    palette: usize,
 }
 
 impl Player {
@@ -149,6 +388,7 @@ impl Player {
             fsm,
             state: initial_state,
             scope: Scope::new(),
+            palette: 0,
         };
 
         // evaluate idle script
@@ -172,6 +412,38 @@ impl Player {
         self.state.pos
     }
 
+    /// The palette the player is drawn in. See [`Fsm::palette`].
+    pub fn palette(&self) -> usize {
+        self.palette
+    }
+
+    /// Sets the palette the player is drawn in.
+    ///
+    /// Palettes the [`Fsm`] doesn't have wrap around to the ones it does.
+    pub fn set_palette(&mut self, palette: usize) {
+        self.palette = palette % self.fsm.palette_count();
+    }
+
+    /// Swaps the player's [`Fsm`] for another, keeping their position.
+    ///
+    /// The state script is run again from scratch. If the new `Fsm` doesn't
+    /// have the state the player is in, the player goes back to `"idle"`.
+    pub fn replace(&mut self, engine: &Engine, fsm: Fsm) -> Result<(), Error> {
+        match fsm.get(&self.state.key) {
+            Some(state) if state.frame(self.state.frame).is_some() => (),
+            Some(_) => self.state.frame = 0,
+            None => {
+                self.state.key = Key::from("idle");
+                self.state.frame = 0;
+            }
+        }
+
+        self.fsm = fsm;
+        self.scope = Scope::new();
+
+        eval(&self.state.key, &self.fsm, engine, &mut self.scope)
+    }
+
     /// Updates the player's state in respect to the inputs given.
     pub fn update(&mut self, engine: &Engine, inputs: &InputBuffer) -> Result<(), Error> {
         self.scope.push("inputs", inputs.clone());
@@ -232,11 +504,57 @@ impl Player {
 
             let mut sprite = sprite.clone();
             sprite.set_transform(sprite.transform() * transform);
+            sprite.set_tint(sprite.tint() * self.state.tint);
+            sprite.set_flash(self.state.flash);
+            sprite.set_opacity(sprite.opacity() * self.state.opacity);
+
+            if let Some(palette) = self.fsm.palette(self.palette) {
+                sprite.set_palette(Some(palette.clone()));
+            }
+
             sprite.draw(cx);
         }
 
         Ok(())
     }
+
+    /// Draws the boxes of the player's frame, and its origin and facing.
+    pub fn draw_debug(&self, cx: &mut Renderer) -> Result<(), Error> {
+        let frame = self
+            .fsm
+            .get(&self.state.key)
+            .ok_or_else(|| anyhow!("player in an invalid state"))?
+            .frame(self.state.frame)
+            .ok_or_else(|| anyhow!("player in an invalid frame"))?;
+
+        let mut transform = Affine2::from_translation(self.state.pos);
+
+        if self.state.flipped {
+            transform = transform * Affine2::from_scale(Vec2::new(-1.0, 1.0));
+        }
+
+        if let Some(pushbox) = &frame.pushbox {
+            cx.fill_rect(pushbox, transform, PUSHBOX_COLOR);
+        }
+
+        for hurtbox in frame.hurtboxes.iter() {
+            cx.fill_rect(hurtbox, transform, HURTBOX_COLOR);
+        }
+
+        for hitbox in frame.hitboxes.iter() {
+            cx.fill_rect(hitbox, transform, HITBOX_COLOR);
+        }
+
+        // the origin, with a line pointing the way the player faces
+        cx.fill_rect(
+            &Rect::new(-0.03, -0.03, 0.03, 0.03),
+            transform,
+            Color::WHITE,
+        );
+        cx.fill_rect(&Rect::new(0., -0.01, 0.2, 0.01), transform, Color::WHITE);
+
+        Ok(())
+    }
 }
 
 fn eval(key: &str, fsm: &Fsm, engine: &Engine, scope: &mut Scope<'static>) -> Result<(), Error> {
@@ -264,6 +582,18 @@ pub struct State {
     /// If the entity is flipped. Entities normally face right, so if the entity
     /// is `flipped`, they would be facing left.
     pub flipped: bool,
+    /// The health of the entity, from `0` to [`MAX_HEALTH`].
+    pub health: i32,
+    /// The meter of the entity, from `0` to [`MAX_METER`].
+    pub meter: i32,
+
+    /// The colour the sprite of the entity is multiplied with.
+    pub tint: Color,
+    /// The colour added to the sprite of the entity, by its alpha. See
+    /// [`Sprite::set_flash`](crate::render::Sprite::set_flash).
+    pub flash: Color,
+    /// The opacity of the sprite of the entity, from `0` to `1`.
+    pub opacity: f32,
 
     /// The key of the state of the entity.
     pub key: Key,
@@ -277,6 +607,11 @@ impl State {
         State {
             pos: Vec2::new(-1., 0.),
             flipped: false,
+            health: MAX_HEALTH,
+            meter: 0,
+            tint: Color::WHITE,
+            flash: Color::TRANSPARENT,
+            opacity: 1.,
             key: Key::from("idle"),
             frame: 0,
         }
@@ -287,6 +622,11 @@ impl State {
         State {
             pos: Vec2::new(1., 0.),
             flipped: true,
+            health: MAX_HEALTH,
+            meter: 0,
+            tint: Color::WHITE,
+            flash: Color::TRANSPARENT,
+            opacity: 1.,
             key: Key::from("idle"),
             frame: 0,
         }
@@ -303,7 +643,177 @@ impl Hash for State {
         hasher.write(&self.pos.y.to_ne_bytes());
 
         self.flipped.hash(hasher);
+        self.health.hash(hasher);
+        self.meter.hash(hasher);
+
+        // scripts can read these back, so they are part of the game
+        for color in [self.tint, self.flash] {
+            for channel in <[f32; 4]>::from(color) {
+                hasher.write(&channel.to_ne_bytes());
+            }
+        }
+        hasher.write(&self.opacity.to_ne_bytes());
         self.key.hash(hasher);
         self.frame.hash(hasher);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::render::{DrawCommand, Sprite, Texture};
+
+    fn fsm(texture: &Texture) -> Fsm {
+        Fsm::new([fsm::State {
+            name: Key::from("idle"),
+            frames: vec![fsm::Frame {
+                sprite: Some(Sprite::new(texture.clone())),
+                hitboxes: vec![Rect::new(0.2, 0.4, 0.6, 0.6)],
+                ..Default::default()
+            }],
+            script: None,
+        }])
+    }
+
+    fn arena(texture: &Texture) -> Arena {
+        let fsm = fsm(texture);
+
+        Arena::new(&Engine::new(), fsm.clone(), fsm).unwrap()
+    }
+
+    #[test]
+    fn draw() {
+        let texture = Texture::headless(32, 64);
+        let arena = arena(&texture);
+
+        let mut renderer = Renderer::new(9. / 16.);
+        arena.draw(&mut renderer).unwrap();
+
+        // p2 is drawn first, facing left, so p1 is drawn over it
+        let [p2, p1] = renderer.commands() else {
+            panic!("expected a command for each player");
+        };
+
+        for command in [p1, p2] {
+            assert_eq!(
+                command.texture.as_ref().map(Texture::id),
+                Some(texture.id())
+            );
+            assert_eq!(command.layer, 0);
+            assert_eq!(command.tint, Color::WHITE);
+        }
+
+        // the camera fits both players, and the sprite is half as wide as it
+        // is tall
+        let close = |a: Vec2, b: Vec2| a.abs_diff_eq(b, 1e-5);
+
+        assert!(close(p1.transform.translation, Vec2::new(-0.45, -0.4)));
+        assert!(close(p1.transform.matrix2.x_axis, Vec2::new(0.225, 0.)));
+        assert!(close(p1.transform.matrix2.y_axis, Vec2::new(0., 0.8)));
+
+        assert!(close(p2.transform.translation, Vec2::new(0.45, -0.4)));
+        assert!(close(p2.transform.matrix2.x_axis, Vec2::new(-0.225, 0.)));
+        assert!(close(p2.transform.matrix2.y_axis, Vec2::new(0., 0.8)));
+    }
+
+    #[test]
+    fn draw_debug() {
+        let arena = arena(&Texture::headless(32, 64));
+
+        let mut renderer = Renderer::new(9. / 16.);
+        renderer.set_debug(true);
+        arena.draw(&mut renderer).unwrap();
+
+        // after the sprites, each player has a hitbox, an origin and a facing
+        let overlay = &renderer.commands()[2..];
+        assert_eq!(overlay.len(), 6);
+        assert!(overlay
+            .iter()
+            .all(|c| c.texture.is_none() && c.layer == DEBUG_LAYER));
+
+        // the hitbox of p2 is mirrored to the left of its origin
+        let (p2, p1) = (&overlay[0], &overlay[3]);
+        assert_eq!(p2.tint, HITBOX_COLOR);
+        assert!(p2.transform.translation.x < 0.45);
+        assert!(p1.transform.translation.x > -0.45);
+    }
+
+    #[test]
+    fn walls() {
+        // the players start outside of the walls of such a narrow stage
+        let stage = Stage::new(1. + 2. * WALL_MARGIN, Vec::new());
+        let mut arena = arena(&Texture::headless(32, 64)).with_stage(stage);
+
+        let inputs = InputBuffer::new();
+        arena.update(&Engine::new(), &inputs, &inputs).unwrap();
+
+        let wall = arena.stage().wall();
+        assert!(wall < 1.);
+
+        let [p1, p2] = arena.players();
+        assert_eq!(p1.pos(), Vec2::new(-wall, 0.));
+        assert_eq!(p2.pos(), Vec2::new(wall, 0.));
+    }
+
+    #[test]
+    fn palettes() {
+        let remap = Texture::headless(256, 16);
+        let fsm = fsm(&Texture::headless(32, 64)).with_palettes(vec![remap.clone()]);
+        let palette = |c: &DrawCommand| c.palette.as_ref().map(Texture::id);
+
+        // a mirror match in the same palette moves p2 to the next one
+        let series = Series::new(&Engine::new(), vec![fsm], [0, 0])
+            .unwrap()
+            .with_palettes([1, 1]);
+
+        let mut renderer = Renderer::new(9. / 16.);
+        series.draw(&mut renderer).unwrap();
+
+        let [p2, p1] = renderer.commands() else {
+            panic!("expected a command for each player");
+        };
+        assert_eq!(palette(p1), Some(remap.id()));
+        assert_eq!(palette(p2), None);
+    }
+
+    #[test]
+    fn effects() {
+        let engine = Engine::new();
+        let script = engine
+            .compile(
+                "fn onupdate() {
+                    state.tint = rgba(1.0, 0.5, 0.5, 0.5);
+                    state.flash = rgb(1.0, 1.0, 1.0);
+                    state.opacity = 2.0;
+                }",
+            )
+            .unwrap();
+
+        let idle = Fsm::new([fsm::State {
+            name: Key::from("idle"),
+            frames: vec![fsm::Frame {
+                sprite: Some(Sprite::new(Texture::headless(32, 64))),
+                ..Default::default()
+            }],
+            script: Some(script),
+        }]);
+
+        let mut arena = Arena::new(&engine, idle.clone(), idle).unwrap();
+        let inputs = InputBuffer::new();
+        arena.update(&engine, &inputs, &inputs).unwrap();
+
+        // the effects are in the state, so they are rolled back with it
+        let state = arena.players()[0].state();
+        assert_eq!(state.flash, Color::WHITE);
+        assert_eq!(state.opacity, 1.);
+
+        let mut renderer = Renderer::new(9. / 16.);
+        arena.draw(&mut renderer).unwrap();
+
+        for command in renderer.commands() {
+            assert_eq!(command.tint, Color::rgba(1., 0.5, 0.5, 0.5));
+            assert_eq!(command.flash, Color::WHITE);
+        }
+    }
+}
@@ -1,7 +1,13 @@
 //! A networked battle using [`backroll`].
 
+use super::fsm::Fsm;
+use super::handshake::Handshake;
 use super::script::Scope;
-use super::{Arena, State, FRAMES_PER_SECOND};
+use super::series::{Phase, Series};
+use super::spectate::SpectatorHost;
+use super::stats::NetStats;
+use super::transport::{relay, Link};
+use super::{Arena, Battle, Camera, Combo, Outcome, State, FRAMES_PER_SECOND};
 
 use crate::input::{sampler::Handle as InputHandle, Buffer as InputBuffer, Inputs};
 use crate::render::Renderer;
@@ -17,16 +23,120 @@ use anyhow::Error;
 
 use std::net::{ToSocketAddrs, SocketAddr};
 use std::hash::{Hash, Hasher};
-use std::mem::MaybeUninit;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many frames back a rollback can reach.
+///
+/// Inputs older than this are never predicted again, so they are considered
+/// confirmed and are safe to broadcast to spectators. This is also how long a
+/// finished series keeps running, so the remote gets the inputs that ended it.
+const MAX_ROLLBACK_FRAMES: u32 = 8;
+
+/// How long an interrupted connection is waited on before giving up.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often network statistics are logged.
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The most frame delay that is ever picked automatically.
+const MAX_AUTO_FRAME_DELAY: u32 = 6;
+
+/// How many frames of latency are left for rollback to hide when frame delay
+/// is picked automatically.
+const ROLLBACK_ALLOWANCE: u32 = 2;
 
 /// A networked battle manager with a local player and a remote peer.
 pub struct NetBattle {
-    arena: Arena,
+    series: Series,
     session: P2PSession<NetConfig>,
     _transport: UdpManager,
     // the player at index 0 is left, index 1 is right.
     players: [Player; 2],
+    spectators: Option<SpectatorHost>,
     time_sync: u8,
+    stats: NetStats,
+    stats_logged: Instant,
+    connection: Connection,
+    frame_delay: FrameDelay,
+    // the phase on the last frame, to notice rematches
+    last_phase: Phase,
+}
+
+/// How much frame delay a [`NetBattle`] uses.
+///
+/// Frame delay holds back local inputs for a few frames, so the remote's
+/// inputs have time to arrive before they are needed. More delay means fewer
+/// rollbacks but a less responsive game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameDelay {
+    /// Always this many frames.
+    Fixed(u32),
+    /// Picked from the round-trip time measured before the match.
+    Auto,
+    /// Like [`FrameDelay::Auto`], but picked again before every rematch.
+    Adaptive,
+}
+
+impl FrameDelay {
+    /// The frame delay to start a battle with, given the round-trip time to
+    /// the remote, if it could be measured.
+    pub fn initial(self, rtt: Option<Duration>) -> u32 {
+        match self {
+            FrameDelay::Fixed(frames) => frames,
+            FrameDelay::Auto | FrameDelay::Adaptive => rtt.map_or(0, frame_delay_for),
+        }
+    }
+}
+
+impl Default for FrameDelay {
+    fn default() -> FrameDelay {
+        FrameDelay::Fixed(0)
+    }
+}
+
+impl FromStr for FrameDelay {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FrameDelay, Error> {
+        match s {
+            "auto" => Ok(FrameDelay::Auto),
+            "adaptive" => Ok(FrameDelay::Adaptive),
+            s => s.parse().map(FrameDelay::Fixed).map_err(|_| {
+                anyhow!(
+                    "invalid frame delay {:?}, expected a number, auto or adaptive",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Picks a frame delay for a round-trip time.
+///
+/// Delay covers the one-way latency, except for a couple of frames that are
+/// left to rollback, so the game stays responsive.
+pub fn frame_delay_for(rtt: Duration) -> u32 {
+    let one_way = (rtt.as_secs_f32() / 2. * FRAMES_PER_SECOND as f32).ceil() as u32;
+
+    one_way
+        .saturating_sub(ROLLBACK_ALLOWANCE)
+        .min(MAX_AUTO_FRAME_DELAY)
+}
+
+/// The state of the connection to the remote player of a [`NetBattle`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connection {
+    /// The connection is fine.
+    Connected,
+    /// Nothing has been heard from the remote player since `since`.
+    Interrupted {
+        /// When the connection was interrupted.
+        since: Instant,
+    },
+    /// The remote player is gone.
+    Disconnected,
 }
 
 /// Config for use in initialization of a [`NetBattle`].
@@ -36,37 +146,66 @@ pub enum NetPlayer {
     Local(InputHandle),
     /// A remote player with a [`SocketAddr`] to bind to.
     Remote(SocketAddr),
+    /// A spectator with a [`SocketAddr`] to broadcast confirmed inputs to.
+    ///
+    /// Spectators do not count towards the two players of a battle. See
+    /// [`SpectateBattle`](super::SpectateBattle) for the other end.
+    Spectator(SocketAddr),
 }
 
 impl NetBattle {
     /// Creates a new `NetBattle` with a given config.
     ///
     /// This does not perform any I/O and just sets up reading and writing. The
-    /// [`Arena`] passed must have been synced beforehand. This struct can also
-    /// be used to spectate games!
+    /// [`Series`] passed must have been synced beforehand. Games can be
+    /// spectated by passing [`NetPlayer::Spectator`]s, in any position.
+    ///
+    /// The connection is kept for as many rematches as the players want. Once
+    /// a player leaves or the connection is lost, the battle ends with an
+    /// [`Outcome`](Battle::outcome).
+    ///
+    /// The frame delay is taken from the [`Args`](crate::config::Args). If it
+    /// is picked automatically, `rtt` should be the round-trip time measured
+    /// to the remote, like by a [`handshake`](super::handshake::exchange).
     ///
-    /// # Panics
-    /// Panics if more than one local player is supplied. Only give one!
+    /// `handshake` describes the game this peer built. It is sent to
+    /// spectators before anything else, so they can check they built the
+    /// same one.
+    ///
+    /// Returns an error if there aren't exactly two players, not counting
+    /// spectators. Only give one local player!
     pub fn new(
         cx: &mut Context,
-        arena: Arena,
+        series: Series,
         bind_addrs: impl ToSocketAddrs,
-        in_players: &[NetPlayer; 2],
+        in_players: &[NetPlayer],
+        rtt: Option<Duration>,
+        handshake: Handshake,
     ) -> Result<NetBattle, Error> {
+        let bind_addr = bind_addrs
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("no address to bind to"))?;
+
         // initialize transport
-        let transport = UdpManager::bind(cx.task_pool.clone(), bind_addrs)?;
+        let transport = UdpManager::bind(cx.task_pool.clone(), bind_addr)?;
+
+        let frame_delay = cx.args.frame_delay;
+        let delay = frame_delay.initial(rtt);
+        info!("using a frame delay of {} ({:?})", delay, frame_delay);
 
         // initialize session
-        let mut session = P2PSessionBuilder::<NetConfig>::new().with_frame_delay(0);
+        let mut session = P2PSessionBuilder::<NetConfig>::new().with_frame_delay(delay as _);
 
-        let mut players: [MaybeUninit<Player>; 2] = MaybeUninit::uninit_array();
-        
-        for (i, player) in in_players.into_iter().enumerate() {
+        let mut players = Vec::with_capacity(2);
+        let mut spectators = Vec::new();
+
+        for player in in_players.iter() {
             match player {
                 NetPlayer::Local(p) => {
                     let handle = session.add_player(backroll::Player::Local);
-                    
-                    players[i] = MaybeUninit::new(Player {
+
+                    players.push(Player {
                         kind: PlayerKind::Local(*p),
                         handle,
                         inputs: Default::default(),
@@ -74,68 +213,110 @@ impl NetBattle {
                 }
                 NetPlayer::Remote(addr) => {
                     let peer = transport.connect(UdpConnectionConfig::bounded(*addr, 5));
+                    let (peer, link) = relay(&cx.task_pool, peer, cx.args.conditions.as_ref());
                     let handle = session.add_player(backroll::Player::Remote(peer));
 
-                    players[i] = MaybeUninit::new(Player {
-                        kind: PlayerKind::Remote,
+                    players.push(Player {
+                        kind: PlayerKind::Remote(link),
                         handle,
                         inputs: Default::default(),
                     });
                 }
+                NetPlayer::Spectator(addr) => spectators.push(*addr),
             }
         }
 
+        let players: [Player; 2] = players
+            .try_into()
+            .map_err(|p: Vec<_>| anyhow!("expected 2 players, got {}", p.len()))?;
+
+        // spectators are broadcast to on their own socket
+        let spectators = if spectators.is_empty() {
+            None
+        } else {
+            let host = SpectatorHost::bind((bind_addr.ip(), 0), handshake, spectators)?;
+            info!("broadcasting to spectators from {}", host.local_addr()?);
+            Some(host)
+        };
+
         let session = session.start(cx.task_pool.clone())?;
+        let last_phase = series.phase();
 
         Ok(NetBattle {
-            arena,
+            series,
             session,
             _transport: transport,
-            // SAFETY: the `in_players` array passed must be at least 2, the
-            // length of the uninit array. the loop above initializes this array
-            players: unsafe { MaybeUninit::array_assume_init(players) },
+            players,
+            spectators,
             time_sync: 0,
+            stats: NetStats {
+                frame_delay: delay,
+                ..Default::default()
+            },
+            stats_logged: Instant::now(),
+            connection: Connection::Connected,
+            frame_delay,
+            last_phase,
         })
     }
 
-    /// Polls an update for the `NetBattle`.
-    pub fn update(&mut self, cx: &mut Context) -> Result<(), Error> {
-        self.handle_commands(cx, self.session.poll())?;
+    /// The state of the connection to the remote player.
+    pub fn connection(&self) -> Connection {
+        self.connection
+    }
 
-        'update: while cx.frame_limiter.should_update(FRAMES_PER_SECOND) {
-            if self.time_sync > 0 {
-                // skip this frame
-                self.time_sync -= 1;
-                continue;
-            }
+    /// The network statistics of the battle.
+    pub fn stats(&self) -> &NetStats {
+        &self.stats
+    }
 
-            // only run logic if the session is synchronized
-            if self.session.is_synchronized() {
-                // sample input from the local player(s)
-                for player in self.players.iter() {
-                    if let Some(input) = player.sample_local(cx) {
-                        match self.session.add_local_input(player.handle, input) {
-                            Ok(()) => (),
-                            Err(BackrollError::ReachedPredictionBarrier) => {
-                                warn!("skipping rollback frame {}", self.arena.frame());
-                                continue 'update;
-                            }
-                            Err(e) => return Err(e.into()),
-                        };
-                    }
-                }
+    /// Picks the frame delay again from the current ping, if it is adaptive.
+    fn adapt_frame_delay(&mut self) -> Result<(), Error> {
+        if self.frame_delay != FrameDelay::Adaptive {
+            return Ok(());
+        }
 
-                // handle commands
-                self.handle_commands(cx, self.session.advance_frame())?;
+        let delay = frame_delay_for(self.stats.ping);
+
+        if delay != self.stats.frame_delay {
+            info!(
+                "frame delay {} -> {} for ping {}ms",
+                self.stats.frame_delay,
+                delay,
+                self.stats.ping.as_millis()
+            );
+
+            for player in self.players.iter() {
+                if let PlayerKind::Local(_) = player.kind {
+                    self.session.set_frame_delay(player.handle, delay as _)?;
+                }
             }
+
+            self.stats.frame_delay = delay;
         }
 
         Ok(())
     }
 
-    /// Draws the battle to a graphics context.
-    pub fn draw(&mut self, cx: &mut Renderer) -> Result<(), Error> {
-        self.arena.draw(cx)
+    fn update_stats(&mut self) {
+        for player in self.players.iter() {
+            if let PlayerKind::Remote(link) = &player.kind {
+                if let Ok(stats) = self.session.get_network_stats(player.handle) {
+                    self.stats.ping = stats.ping;
+                    self.stats.kbps_sent = stats.kbps_sent;
+                    self.stats.local_frames_behind = stats.local_frames_behind;
+                    self.stats.remote_frames_behind = stats.remote_frames_behind;
+                }
+
+                self.stats.packets_lost = link.lost();
+                self.stats.packet_loss = link.loss();
+            }
+        }
+
+        if self.stats_logged.elapsed() >= STATS_LOG_INTERVAL {
+            self.stats_logged = Instant::now();
+            debug!(target: "bftd::netstats", "frame={} {}", self.series.frame(), self.stats);
+        }
     }
 
     fn handle_commands(
@@ -148,30 +329,70 @@ impl NetBattle {
                 Command::AdvanceFrame(inputs) => {
                     // do game logic here!
                     for player in self.players.iter_mut() {
-                        player.inputs.push(*inputs.get(player.handle).unwrap());
+                        // a disconnected player stops pressing anything
+                        let input = match inputs.get(player.handle) {
+                            Ok(input) => *input,
+                            Err(_) => Inputs::default(),
+                        };
+
+                        player.inputs.push(input);
                     }
 
-                    self.arena.update(
+                    self.series.update(
                         &cx.script,
                         &self.players[0].inputs,
                         &self.players[1].inputs,
                     )?;
+
+                    let inputs = [self.players[0].inputs.last(), self.players[1].inputs.last()];
+
+                    self.stats.record_frame(self.series.frame(), inputs);
+
+                    if let Some(spectators) = &mut self.spectators {
+                        spectators.record(self.series.frame(), inputs);
+                    }
                 }
                 Command::Save(save) => {
                     // take a snapshot
-                    let snapshot = ArenaSnapshot::snapshot(&self.arena);
+                    let inputs = [&self.players[0].inputs, &self.players[1].inputs];
+                    let snapshot = SeriesSnapshot::snapshot(&self.series, inputs);
 
                     save.save(snapshot);
                 }
                 Command::Load(save) => {
                     // load snapshot.
-                    save.load().impose(&mut self.arena);
+                    let from = self.series.frame();
+                    let inputs = [&self.players[0].inputs, &self.players[1].inputs];
+                    save.load().impose(&mut self.series, inputs);
+
+                    self.stats.record_rollback(from, self.series.frame());
                 }
                 Command::Event(ev) => match ev {
                     Event::TimeSync { frames_ahead } if frames_ahead > 0 => {
                         warn!("stalling {} frames to let remote catch up.", frames_ahead);
                         self.time_sync = frames_ahead;
+                        self.stats.time_sync_frames = frames_ahead;
                     },
+                    Event::ConnectionInterrupted { player, .. } => {
+                        warn!("connection to player {:?} interrupted", player);
+
+                        if self.connection == Connection::Connected {
+                            self.connection = Connection::Interrupted {
+                                since: Instant::now(),
+                            };
+                        }
+                    }
+                    Event::ConnectionResumed(player) => {
+                        info!("connection to player {:?} resumed", player);
+
+                        if let Connection::Interrupted { .. } = self.connection {
+                            self.connection = Connection::Connected;
+                        }
+                    }
+                    Event::Disconnected(player) => {
+                        warn!("player {:?} disconnected", player);
+                        self.connection = Connection::Disconnected;
+                    }
                     _ => (),
                 },
             }
@@ -181,6 +402,100 @@ impl NetBattle {
     }
 }
 
+impl Battle for NetBattle {
+    fn update(&mut self, cx: &mut Context) -> Result<(), Error> {
+        self.handle_commands(cx, self.session.poll())?;
+
+        if let Connection::Interrupted { since } = self.connection {
+            if since.elapsed() >= DISCONNECT_TIMEOUT {
+                warn!("gave up on the connection after {:?}", DISCONNECT_TIMEOUT);
+                self.connection = Connection::Disconnected;
+            }
+        }
+
+        if self.outcome().is_some() {
+            return Ok(());
+        }
+
+        'update: while cx.frame_limiter.should_update(FRAMES_PER_SECOND) {
+            if self.time_sync > 0 {
+                // skip this frame
+                self.time_sync -= 1;
+                continue;
+            }
+
+            // only run logic if the session is synchronized
+            if self.session.is_synchronized() {
+                // sample input from the local player(s)
+                for player in self.players.iter() {
+                    if let Some(input) = player.sample_local(cx) {
+                        match self.session.add_local_input(player.handle, input) {
+                            Ok(()) => (),
+                            Err(BackrollError::ReachedPredictionBarrier) => {
+                                warn!("skipping rollback frame {}", self.series.frame());
+                                continue 'update;
+                            }
+                            Err(e) => return Err(e.into()),
+                        };
+                    }
+                }
+
+                // handle commands
+                self.handle_commands(cx, self.session.advance_frame())?;
+
+                // broadcast everything that can no longer be rolled back
+                if let Some(spectators) = &mut self.spectators {
+                    spectators.poll(self.series.frame().saturating_sub(MAX_ROLLBACK_FRAMES))?;
+                }
+
+                self.update_stats();
+
+                // between rounds is the only time delay can change unnoticed
+                let phase = self.series.phase();
+                if matches!(self.last_phase, Phase::PostMatch { .. }) && phase == Phase::Fighting {
+                    self.adapt_frame_delay()?;
+                }
+                self.last_phase = phase;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn draw(&mut self, cx: &mut Renderer) -> Result<(), Error> {
+        self.series.draw(cx)
+    }
+
+    fn net_stats(&self) -> Option<&NetStats> {
+        Some(&self.stats)
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        if self.connection == Connection::Disconnected {
+            return Some(Outcome::Disconnected);
+        }
+
+        // wait until the frame the series ended on can't be rolled back
+        match self.series.phase() {
+            Phase::Finished { at } if self.series.frame() >= at + MAX_ROLLBACK_FRAMES => {
+                self.series.outcome()
+            }
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        match self.connection {
+            Connection::Interrupted { since } => Some(format!(
+                "connection interrupted, disconnecting in {}s",
+                DISCONNECT_TIMEOUT.saturating_sub(since.elapsed()).as_secs()
+            )),
+            Connection::Disconnected => Some("disconnected".into()),
+            Connection::Connected => None,
+        }
+    }
+}
+
 impl Player {
     fn sample_local(&self, cx: &mut Context) -> Option<Inputs> {
         match self.kind {
@@ -193,7 +508,7 @@ impl Player {
 struct NetConfig;
 
 impl backroll::Config for NetConfig {
-    type State = ArenaSnapshot;
+    type State = SeriesSnapshot;
     type Input = Inputs;
 }
 
@@ -205,34 +520,95 @@ struct Player {
 
 enum PlayerKind {
     Local(InputHandle),
-    Remote,
+    Remote(Arc<Link>),
 }
 
 #[derive(Clone, Hash)]
+struct SeriesSnapshot {
+    frame: u32,
+    picks: [usize; 2],
+    phase: Phase,
+    wins: [u32; 2],
+    round: u32,
+    arena: ArenaSnapshot,
+    // how many inputs each player had. input buffers are only ever pushed
+    // to, so the history up to here is still intact when this is loaded and
+    // only has to be cut back to this length.
+    inputs: [usize; 2],
+}
+
+#[derive(Clone)]
 struct ArenaSnapshot {
+    frame: u32,
     p1: PlayerSnapshot,
     p2: PlayerSnapshot,
+    combos: [Combo; 2],
+    // only moves the view, but has to be rolled back so the view lands
+    // where it would have without the rollback
+    camera: Camera,
 }
 
 #[derive(Clone)]
 struct PlayerSnapshot {
+    // the arena may have been rebuilt with other characters since
+    fsm: Fsm,
     scope: Scope<'static>,
     state: State,
+    palette: usize,
+}
+
+impl SeriesSnapshot {
+    /// Takes a snapshot of the series and the input history of the players.
+    pub fn snapshot(series: &Series, inputs: [&InputBuffer; 2]) -> SeriesSnapshot {
+        SeriesSnapshot {
+            frame: series.frame,
+            picks: series.picks,
+            phase: series.phase,
+            wins: series.wins,
+            round: series.round,
+            arena: ArenaSnapshot::snapshot(&series.arena),
+            inputs: inputs.map(InputBuffer::len),
+        }
+    }
+
+    /// Imposes this snapshot upon a series and the input history of the
+    /// players.
+    ///
+    /// Inputs pushed after the snapshot was taken are dropped, so scripts
+    /// reading the history see exactly what they saw the first time.
+    pub fn impose(self, series: &mut Series, inputs: [&InputBuffer; 2]) {
+        series.frame = self.frame;
+        series.picks = self.picks;
+        series.phase = self.phase;
+        series.wins = self.wins;
+        series.round = self.round;
+        self.arena.impose(&mut series.arena);
+
+        for (inputs, len) in inputs.into_iter().zip(self.inputs) {
+            inputs.truncate(len);
+        }
+    }
 }
 
 impl ArenaSnapshot {
     /// Takes a snapshot of the arena.
     pub fn snapshot(arena: &Arena) -> ArenaSnapshot {
         ArenaSnapshot {
+            frame: arena.frame,
             p1: PlayerSnapshot::snapshot(&arena.p1),
             p2: PlayerSnapshot::snapshot(&arena.p2),
+            combos: arena.combos,
+            camera: arena.camera.clone(),
         }
     }
 
     /// Imposes this snapshot upon an arena.
     pub fn impose(self, arena: &mut Arena) {
+        arena.frame = self.frame;
         self.p1.impose(&mut arena.p1);
         self.p2.impose(&mut arena.p2);
+        arena.combos = self.combos;
+        arena.camera = self.camera;
     }
 }
 
@@ -240,15 +616,32 @@ impl PlayerSnapshot {
     /// Takes a snapshot of the player.
     pub fn snapshot(player: &super::Player) -> PlayerSnapshot {
         PlayerSnapshot {
+            fsm: player.fsm.clone(),
             scope: player.scope.clone_visible(),
             state: player.state.clone(),
+            palette: player.palette,
         }
     }
 
     /// Imposes this snapshot upon a player.
     pub fn impose(self, player: &mut super::Player) {
+        player.fsm = self.fsm;
         player.scope = self.scope;
         player.state = self.state;
+        player.palette = self.palette;
+    }
+}
+
+impl Hash for ArenaSnapshot {
+    fn hash<H>(&self, h: &mut H)
+    where
+        H: Hasher,
+    {
+        // the camera has no effect on the game
+        self.frame.hash(h);
+        self.p1.hash(h);
+        self.p2.hash(h);
+        self.combos.hash(h);
     }
 }
 
@@ -257,8 +650,86 @@ impl Hash for PlayerSnapshot {
     where
         H: Hasher,
     {
-        // only hash state LOL
+        // only hash state LOL, the palette has no effect on the game either
+        self.fsm.digest().hash(h);
         self.state.hash(h);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::battle::fsm::{self, Key};
+    use crate::battle::script::Engine;
+    use crate::input::Direction;
+    use crate::render::{Sprite, Texture};
+
+    #[test]
+    fn frame_delay() {
+        assert_eq!("3".parse::<FrameDelay>().unwrap(), FrameDelay::Fixed(3));
+        assert_eq!("auto".parse::<FrameDelay>().unwrap(), FrameDelay::Auto);
+        assert!("soon".parse::<FrameDelay>().is_err());
+
+        // rollback hides a short ping completely
+        assert_eq!(frame_delay_for(Duration::from_millis(40)), 0);
+        // 50ms one way is 3 frames, 2 of which are left to rollback
+        assert_eq!(frame_delay_for(Duration::from_millis(100)), 1);
+        assert_eq!(
+            frame_delay_for(Duration::from_secs(1)),
+            MAX_AUTO_FRAME_DELAY
+        );
+
+        assert_eq!(
+            FrameDelay::Fixed(2).initial(Some(Duration::from_secs(1))),
+            2
+        );
+        assert_eq!(FrameDelay::Adaptive.initial(None), 0);
+    }
+
+    #[test]
+    fn snapshot() {
+        let engine = Engine::new();
+        let fsm = Fsm::new([fsm::State {
+            name: Key::from("idle"),
+            frames: vec![fsm::Frame {
+                sprite: Some(Sprite::new(Texture::headless(32, 64))),
+                ..Default::default()
+            }],
+            script: None,
+        }])
+        .with_palettes(vec![Texture::headless(256, 16)]);
+        let mut series = Series::new(&engine, vec![fsm], [0, 0]).unwrap();
+
+        let inputs = [InputBuffer::new(), InputBuffer::new()];
+        let advance = |series: &mut Series, frames: usize, input: Inputs| {
+            for _ in 0..frames {
+                inputs.iter().for_each(|buffer| buffer.push(input));
+                series.update(&engine, &inputs[0], &inputs[1]).unwrap();
+            }
+        };
+
+        let neutral = Inputs::default();
+        let forward = Inputs {
+            direction: Direction::D6,
+            ..Default::default()
+        };
+
+        advance(&mut series, 3, neutral);
+        let snapshot = SeriesSnapshot::snapshot(&series, [&inputs[0], &inputs[1]]);
+        let frame = series.frame();
+
+        // the remote turns out to have held neutral instead
+        advance(&mut series, 2, forward);
+        series.arena.p1.set_palette(1);
+        snapshot.impose(&mut series, [&inputs[0], &inputs[1]]);
+
+        assert_eq!(series.frame(), frame);
+        assert_eq!(series.arena().p1.palette(), 0);
+        for buffer in inputs.iter() {
+            assert_eq!(buffer.len(), 3);
+            assert_eq!(buffer.last(), neutral);
+            assert_eq!(buffer.previous(), neutral);
+        }
+    }
+}
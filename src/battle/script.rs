@@ -2,13 +2,14 @@
 
 use rhai::{
     packages::{Package, StandardPackage},
-    Module, Shared,
+    Module, Shared, INT,
 };
 pub use rhai::{Scope, AST};
 
 use super::fsm::Key;
-use super::State;
+use super::{State, MAX_HEALTH, MAX_METER};
 use crate::input::{Buffer, Direction};
+use crate::render::Color;
 
 use std::ops::{Add, Deref, Div, Mul, Sub};
 
@@ -56,6 +57,14 @@ impl Engine {
             .register_fn("/", <Vec2 as Div<Vec2>>::div)
             .register_fn("*", <Vec2 as Mul<f32>>::mul)
             .register_fn("*", <f32 as Mul<Vec2>>::mul)
+            // Color impl
+            .register_type::<Color>()
+            .register_fn("rgba", Color::rgba)
+            .register_fn("rgb", |r: f32, g: f32, b: f32| Color::rgba(r, g, b, 1.))
+            .register_get_set("r", |c: &mut Color| c.r, |c: &mut Color, r: f32| c.r = r)
+            .register_get_set("g", |c: &mut Color| c.g, |c: &mut Color, g: f32| c.g = g)
+            .register_get_set("b", |c: &mut Color| c.b, |c: &mut Color, b: f32| c.b = b)
+            .register_get_set("a", |c: &mut Color| c.a, |c: &mut Color, a: f32| c.a = a)
             // Direction impl
             .register_type::<Direction>()
             .register_fn("==", |d1: Direction, d2: Direction| d1 == d2)
@@ -82,6 +91,31 @@ impl Engine {
                 },
             )
             .register_get("flipped", |s: &mut State| s.flipped)
+            .register_get_set(
+                "health",
+                |s: &mut State| s.health as INT,
+                |s: &mut State, health: INT| s.health = health.clamp(0, MAX_HEALTH as INT) as i32,
+            )
+            .register_get_set(
+                "meter",
+                |s: &mut State| s.meter as INT,
+                |s: &mut State, meter: INT| s.meter = meter.clamp(0, MAX_METER as INT) as i32,
+            )
+            .register_get_set(
+                "tint",
+                |s: &mut State| s.tint,
+                |s: &mut State, tint: Color| s.tint = tint,
+            )
+            .register_get_set(
+                "flash",
+                |s: &mut State| s.flash,
+                |s: &mut State, flash: Color| s.flash = flash,
+            )
+            .register_get_set(
+                "opacity",
+                |s: &mut State| s.opacity,
+                |s: &mut State, opacity: f32| s.opacity = opacity.clamp(0., 1.),
+            )
             .register_fn("change", |s: &mut State, name: &str| {
                 s.key = Key::from(name)
             });
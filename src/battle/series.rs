@@ -0,0 +1,330 @@
+//! A series of matches between the same two players.
+//!
+//! When a match ends, the series moves on to a post-match phase. There, each
+//! player can cycle through the roster to pick another character with
+//! [`Direction::D4`] and [`Direction::D6`], agree to a rematch with
+//! [`Buttons::P`] or leave with [`Buttons::K`]. Once both players agree to a
+//! rematch, a new [`Arena`] is built with their picks. If either player
+//! leaves, the series is over.
+//!
+//! The series keeps score: how many matches each player has won, and which
+//! match is being fought.
+//!
+//! Everything here is driven by the inputs of each frame, so it can be rolled
+//! back and replayed like any other part of the battle. This is what lets a
+//! [`NetBattle`](super::NetBattle) play any number of matches over the same
+//! connection.
+
+use super::fsm::Fsm;
+use super::script::Engine;
+use super::{Arena, Hud, Outcome, Stage};
+
+use crate::assets::Digest;
+use crate::input::{Buffer as InputBuffer, Buttons, Direction};
+use crate::render::Renderer;
+
+use anyhow::Error;
+
+use std::hash::Hasher;
+
+/// A series of matches.
+pub struct Series {
+    roster: Vec<Fsm>,
+    stage: Stage,
+    palettes: [usize; 2],
+    pub(super) arena: Arena,
+    pub(super) picks: [usize; 2],
+    pub(super) phase: Phase,
+    pub(super) frame: u32,
+    pub(super) wins: [u32; 2],
+    pub(super) round: u32,
+    hud: Option<Hud>,
+}
+
+/// The phase a [`Series`] is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// A match is being fought.
+    Fighting,
+    /// The match is over, and the players are deciding what to do next.
+    PostMatch {
+        /// The choice of each player.
+        choices: [Choice; 2],
+    },
+    /// A player left on the frame `at`.
+    Finished {
+        /// The frame the series ended on.
+        at: u32,
+    },
+}
+
+/// A player's choice after a match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Choice {
+    /// The player hasn't decided yet.
+    Undecided,
+    /// The player wants a rematch.
+    Rematch,
+    /// The player is leaving.
+    Leave,
+}
+
+impl Series {
+    /// Creates a new `Series`, starting a match between the characters at
+    /// `picks` in the `roster`.
+    pub fn new(engine: &Engine, roster: Vec<Fsm>, picks: [usize; 2]) -> Result<Series, Error> {
+        let stage = Stage::void();
+        let palettes = [0; 2];
+        let arena = build_arena(engine, &roster, &stage, palettes, picks)?;
+
+        Ok(Series {
+            roster,
+            stage,
+            palettes,
+            arena,
+            picks,
+            phase: Phase::Fighting,
+            frame: 0,
+            wins: [0; 2],
+            round: 1,
+            hud: None,
+        })
+    }
+
+    /// Fights every match of the series on `stage`.
+    ///
+    /// This should be done before the first frame is processed.
+    pub fn with_stage(self, stage: Stage) -> Series {
+        Series {
+            arena: self.arena.with_stage(stage.clone()),
+            stage,
+            ..self
+        }
+    }
+
+    /// Draws the players in the given palettes, left then right.
+    ///
+    /// If both players pick the same character in the same palette, the
+    /// right player is drawn in the next one, so they can be told apart.
+    pub fn with_palettes(self, palettes: [usize; 2]) -> Series {
+        let arena = self
+            .arena
+            .with_palettes(mirror(&self.roster, self.picks, palettes));
+
+        Series {
+            arena,
+            palettes,
+            ..self
+        }
+    }
+
+    /// Draws `hud` over the arena.
+    pub fn with_hud(self, hud: Hud) -> Series {
+        Series {
+            hud: Some(hud),
+            ..self
+        }
+    }
+
+    /// Processes the next frame of the series.
+    pub fn update(
+        &mut self,
+        engine: &Engine,
+        p1: &InputBuffer,
+        p2: &InputBuffer,
+    ) -> Result<(), Error> {
+        self.frame += 1;
+
+        match &mut self.phase {
+            Phase::Fighting => {
+                self.arena.update(engine, p1, p2)?;
+
+                if self.arena.is_over() {
+                    info!("match over on frame {}", self.frame);
+
+                    if let Some(winner) = self.arena.winner() {
+                        self.wins[winner] += 1;
+                    }
+
+                    self.phase = Phase::PostMatch {
+                        choices: [Choice::Undecided; 2],
+                    };
+                }
+            }
+            Phase::PostMatch { choices } => {
+                let len = self.roster.len();
+
+                for (i, inputs) in [p1, p2].into_iter().enumerate() {
+                    // choices are final
+                    if choices[i] != Choice::Undecided {
+                        continue;
+                    }
+
+                    if inputs.tapped(Direction::D4) {
+                        self.picks[i] = (self.picks[i] + len - 1) % len;
+                    } else if inputs.tapped(Direction::D6) {
+                        self.picks[i] = (self.picks[i] + 1) % len;
+                    }
+
+                    let pressed = inputs.pressed();
+
+                    if pressed.contains(Buttons::K) {
+                        choices[i] = Choice::Leave;
+                    } else if pressed.contains(Buttons::P) {
+                        choices[i] = Choice::Rematch;
+                    }
+                }
+
+                if choices.contains(&Choice::Leave) {
+                    info!("series over on frame {}", self.frame);
+                    self.phase = Phase::Finished { at: self.frame };
+                } else if choices.iter().all(|&c| c == Choice::Rematch) {
+                    info!("rematch on frame {}", self.frame);
+                    self.arena =
+                        build_arena(engine, &self.roster, &self.stage, self.palettes, self.picks)?;
+                    self.phase = Phase::Fighting;
+                    self.round += 1;
+                }
+            }
+            Phase::Finished { .. } => (),
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the character at `index` in the roster.
+    ///
+    /// Players playing the character switch to the new one in place, as
+    /// described in [`Player::replace`](super::Player::replace).
+    pub fn replace_character(
+        &mut self,
+        engine: &Engine,
+        index: usize,
+        fsm: Fsm,
+    ) -> Result<(), Error> {
+        let len = self.roster.len();
+        let slot = self
+            .roster
+            .get_mut(index)
+            .ok_or_else(|| anyhow!("character {} out of range of roster of {}", index, len))?;
+        *slot = fsm.clone();
+
+        for (side, pick) in self.picks.into_iter().enumerate() {
+            if pick == index {
+                self.arena.replace(engine, side, fsm.clone())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draws the series to a graphics context.
+    pub fn draw(&self, cx: &mut Renderer) -> Result<(), Error> {
+        self.arena.draw(cx)?;
+
+        if let Some(hud) = &self.hud {
+            hud.draw(self, cx);
+        }
+
+        Ok(())
+    }
+
+    /// The amount of frames that have passed in the series, across matches.
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// The current match.
+    pub fn arena(&self) -> &Arena {
+        &self.arena
+    }
+
+    /// The phase of the series.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// The number of matches each player has won.
+    pub fn wins(&self) -> [u32; 2] {
+        self.wins
+    }
+
+    /// How the series ended, if it is over.
+    pub fn outcome(&self) -> Option<Outcome> {
+        match self.phase {
+            Phase::Finished { .. } => Some(Outcome::Finished { wins: self.wins }),
+            _ => None,
+        }
+    }
+
+    /// The number of the match being fought, or that was fought last,
+    /// starting at `1`.
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    /// The index in the roster of each player's character.
+    pub fn picks(&self) -> [usize; 2] {
+        self.picks
+    }
+
+    /// The characters that can be picked.
+    pub fn roster(&self) -> &[Fsm] {
+        &self.roster
+    }
+
+    /// A digest of the characters in the roster, in order, and the stage.
+    ///
+    /// Two series with the same digest run the same character code on the
+    /// same stage, so this can be used to check that two peers built their
+    /// series the same way.
+    pub fn digest(&self) -> u64 {
+        let mut digest = Digest::new();
+
+        for fsm in self.roster.iter() {
+            digest.write_u64(fsm.digest());
+        }
+
+        digest.write_u64(self.stage.digest());
+
+        digest.finish()
+    }
+}
+
+fn build_arena(
+    engine: &Engine,
+    roster: &[Fsm],
+    stage: &Stage,
+    palettes: [usize; 2],
+    picks: [usize; 2],
+) -> Result<Arena, Error> {
+    let [p1, p2] = picks.map(|i| {
+        roster
+            .get(i)
+            .cloned()
+            .ok_or_else(|| anyhow!("pick {} out of range of roster of {}", i, roster.len()))
+    });
+
+    Ok(Arena::new(engine, p1?, p2?)?
+        .with_stage(stage.clone())
+        .with_palettes(mirror(roster, picks, palettes)))
+}
+
+/// Moves the right player to the next palette in a mirror match where both
+/// players picked the same one.
+fn mirror(roster: &[Fsm], picks: [usize; 2], palettes: [usize; 2]) -> [usize; 2] {
+    let [p1, p2] = palettes;
+
+    match roster.get(picks[0]) {
+        Some(fsm) if picks[0] == picks[1] => {
+            let count = fsm.palette_count();
+
+            if p1 % count == p2 % count {
+                [p1, p1 + 1]
+            } else {
+                [p1, p2]
+            }
+        }
+        _ => palettes,
+    }
+}
@@ -0,0 +1,529 @@
+//! Spectating networked battles.
+//!
+//! Spectators do not take part in the rollback session. Instead, a peer of a
+//! [`NetBattle`](super::NetBattle) broadcasts the inputs of every *confirmed*
+//! frame to a list of spectators, who simply play them back on their own
+//! [`Series`]. Since confirmed inputs never change, spectators never have to
+//! roll back.
+//!
+//! The broadcast is a tiny protocol on top of UDP. The host resends every
+//! frame a spectator has not acknowledged yet, so lost packets are simply
+//! retransmitted on the next frame.
+//!
+//! Before any inputs, the host sends its [`Handshake`] until the spectator
+//! acknowledges it. The spectator checks that it built the same game as the
+//! host, and bails out with an error before playing any inputs if it didn't.
+
+use super::handshake::Handshake;
+use super::{Battle, Outcome, Series, FRAMES_PER_SECOND};
+
+use crate::input::{Buffer as InputBuffer, Inputs};
+use crate::render::Renderer;
+use crate::Context;
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Error;
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// The most frames sent to a spectator in a single packet.
+const MAX_FRAMES_PER_PACKET: usize = 32;
+
+/// The size of the receive buffer for spectator packets.
+const PACKET_SIZE: usize = 8192;
+
+/// How long the host can go without sending anything before the stream is
+/// thought to be over.
+const HOST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A spectated battle.
+///
+/// Follows a battle hosted elsewhere by playing back the confirmed inputs a
+/// [`SpectatorHost`] sends to it, `delay` frames behind the latest input
+/// received. Once the host goes quiet, whatever inputs are left are played
+/// without delay, since no more are coming.
+pub struct SpectateBattle {
+    series: Series,
+    client: SpectatorClient,
+    delay: u32,
+    p1: InputBuffer,
+    p2: InputBuffer,
+}
+
+impl SpectateBattle {
+    /// Creates a new `SpectateBattle`, waiting for inputs on `bind_addrs`.
+    ///
+    /// `local` describes the game the [`Series`] was built as. The battle
+    /// fails once the host's handshake comes in if the host built another.
+    pub fn new(
+        series: Series,
+        local: Handshake,
+        bind_addrs: impl ToSocketAddrs,
+        delay: u32,
+    ) -> Result<SpectateBattle, Error> {
+        Ok(SpectateBattle {
+            series,
+            client: SpectatorClient::bind(bind_addrs, local)?,
+            delay,
+            p1: Default::default(),
+            p2: Default::default(),
+        })
+    }
+}
+
+impl Battle for SpectateBattle {
+    fn update(&mut self, cx: &mut Context) -> Result<(), Error> {
+        self.client.poll()?;
+
+        let delay = if self.client.is_silent() {
+            0
+        } else {
+            self.delay
+        };
+
+        while cx.frame_limiter.should_update(FRAMES_PER_SECOND) {
+            // only advance if there is enough of a buffer
+            let next = self.series.frame() as usize;
+
+            if self.client.inputs().len() > next + delay as usize {
+                let [p1, p2] = self.client.inputs()[next];
+
+                self.p1.push(p1);
+                self.p2.push(p2);
+
+                self.series.update(&cx.script, &self.p1, &self.p2)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn draw(&mut self, cx: &mut Renderer) -> Result<(), Error> {
+        self.series.draw(cx)
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        // the stream only ended early if it stopped short of the end
+        let played = self.series.frame() as usize >= self.client.inputs().len();
+
+        self.series
+            .outcome()
+            .or_else(|| (self.client.is_silent() && played).then_some(Outcome::Left))
+    }
+}
+
+/// The broadcasting end of a spectated battle.
+pub struct SpectatorHost {
+    socket: UdpSocket,
+    handshake: Handshake,
+    spectators: Vec<Spectator>,
+    // the inputs of every frame simulated, starting from frame 1
+    history: Vec<[Inputs; 2]>,
+}
+
+struct Spectator {
+    addr: SocketAddr,
+    // if the spectator has received the handshake
+    greeted: bool,
+    // how many frames the spectator has received
+    acked: usize,
+}
+
+impl SpectatorHost {
+    /// Binds a new `SpectatorHost` that broadcasts to `spectators`, greeting
+    /// each with `handshake` first.
+    pub fn bind(
+        bind_addrs: impl ToSocketAddrs,
+        handshake: Handshake,
+        spectators: impl IntoIterator<Item = SocketAddr>,
+    ) -> Result<SpectatorHost, Error> {
+        let socket = UdpSocket::bind(bind_addrs)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(SpectatorHost {
+            socket,
+            handshake,
+            spectators: spectators
+                .into_iter()
+                .map(|addr| Spectator {
+                    addr,
+                    greeted: false,
+                    acked: 0,
+                })
+                .collect(),
+            history: Vec::new(),
+        })
+    }
+
+    /// The address the host is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr().map_err(From::from)
+    }
+
+    /// Records the inputs used to simulate `frame`.
+    ///
+    /// Frames start at `1`. Recording a frame that was already recorded, like
+    /// when the frame is simulated again after a rollback, discards the
+    /// inputs of that frame and every frame after it.
+    pub fn record(&mut self, frame: u32, inputs: [Inputs; 2]) {
+        let index = frame.saturating_sub(1) as usize;
+        debug_assert!(
+            index <= self.history.len(),
+            "frame {} recorded out of order",
+            frame
+        );
+
+        self.history.truncate(index);
+        self.history.push(inputs);
+    }
+
+    /// Processes acknowledgements from spectators and sends them every frame
+    /// up to and including `confirmed` that they have not received yet.
+    ///
+    /// Spectators that haven't acknowledged the handshake are sent that
+    /// instead.
+    pub fn poll(&mut self, confirmed: u32) -> Result<(), Error> {
+        let mut buf = [0u8; PACKET_SIZE];
+
+        // receive acknowledgements
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) => match Packet::decode(&buf[..len]) {
+                    Ok(Packet::Ack { frames }) => {
+                        if let Some(spectator) = self.spectators.iter_mut().find(|s| s.addr == addr)
+                        {
+                            spectator.greeted = true;
+                            spectator.acked = spectator.acked.max(frames as usize);
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => warn!("bad packet from spectator {}: {}", addr, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // a spectator going away shouldn't bring the host down with it
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let confirmed = (confirmed as usize).min(self.history.len());
+
+        for spectator in self.spectators.iter() {
+            let packet = if !spectator.greeted {
+                Packet::Handshake(self.handshake.clone())
+            } else if spectator.acked < confirmed {
+                let end = confirmed.min(spectator.acked + MAX_FRAMES_PER_PACKET);
+
+                Packet::Inputs {
+                    start: spectator.acked as u32,
+                    inputs: self.history[spectator.acked..end].to_vec(),
+                }
+            } else {
+                continue;
+            };
+
+            match self.socket.send_to(&packet.encode()?, spectator.addr) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The receiving end of a spectated battle.
+pub struct SpectatorClient {
+    socket: UdpSocket,
+    local: Handshake,
+    // set once the host's handshake is verified
+    host: Option<SocketAddr>,
+    inputs: Vec<[Inputs; 2]>,
+    // when the host was last heard from
+    last_received: Option<Instant>,
+}
+
+impl SpectatorClient {
+    /// Binds a new `SpectatorClient` for the game described by `local`.
+    ///
+    /// The client does not know about the host until it receives its
+    /// handshake.
+    pub fn bind(
+        bind_addrs: impl ToSocketAddrs,
+        local: Handshake,
+    ) -> Result<SpectatorClient, Error> {
+        let socket = UdpSocket::bind(bind_addrs)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(SpectatorClient {
+            socket,
+            local,
+            host: None,
+            inputs: Vec::new(),
+            last_received: None,
+        })
+    }
+
+    /// The address the client is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr().map_err(From::from)
+    }
+
+    /// The confirmed inputs received so far, starting from frame 1.
+    pub fn inputs(&self) -> &[[Inputs; 2]] {
+        &self.inputs
+    }
+
+    /// If the host has sent something, but nothing for a while since.
+    ///
+    /// A client that hasn't heard from a host yet is still waiting for the
+    /// battle to start, so it isn't silent.
+    pub fn is_silent(&self) -> bool {
+        self.last_received
+            .is_some_and(|at| at.elapsed() >= HOST_TIMEOUT)
+    }
+
+    /// Receives inputs from the host and acknowledges them.
+    ///
+    /// Returns an error if the host's handshake doesn't match the local one.
+    /// Inputs are only taken from a host whose handshake matched.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        let mut buf = [0u8; PACKET_SIZE];
+        let mut received = false;
+
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) => match Packet::decode(&buf[..len]) {
+                    Ok(Packet::Handshake(remote)) if self.host.is_none() => {
+                        self.local.verify_game(&remote).map_err(|e| {
+                            e.context(format!("cannot spectate the battle hosted by {}", addr))
+                        })?;
+
+                        info!("spectating battle hosted by {}", addr);
+                        self.host = Some(addr);
+                        self.last_received = Some(Instant::now());
+                        received = true;
+                    }
+                    // the host hasn't heard the acknowledgement yet
+                    Ok(Packet::Handshake(_)) if self.host == Some(addr) => received = true,
+                    Ok(Packet::Inputs { start, inputs }) if self.host == Some(addr) => {
+                        // only accept inputs that continue where we left off
+                        let start = start as usize;
+                        let len = self.inputs.len();
+
+                        if start <= len && start + inputs.len() > len {
+                            self.inputs.extend_from_slice(&inputs[len - start..]);
+                        }
+
+                        self.last_received = Some(Instant::now());
+                        received = true;
+                    }
+                    Ok(_) => (),
+                    Err(e) => warn!("bad packet from host {}: {}", addr, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if let (true, Some(host)) = (received, self.host) {
+            let packet = Packet::Ack {
+                frames: self.inputs.len() as u32,
+            };
+
+            match self.socket.send_to(&packet.encode()?, host) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Packet {
+    /// The game the host built, sent before any inputs.
+    Handshake(Handshake),
+    /// Confirmed inputs, starting after the frame `start`.
+    Inputs {
+        start: u32,
+        inputs: Vec<[Inputs; 2]>,
+    },
+    /// Acknowledges that the spectator has received `frames` frames.
+    Ack { frames: u32 },
+}
+
+impl Packet {
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        ron::to_string(self)
+            .map(String::into_bytes)
+            .map_err(From::from)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Packet, Error> {
+        ron::de::from_bytes(buf).map_err(From::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::input::{Buttons, Direction};
+
+    use bftd_lib::Metadata;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn handshake() -> Handshake {
+        Handshake {
+            bundles: vec![Metadata::new("Core", semver::Version::new(0, 1, 0))],
+            digest: 0xdead_beef,
+            characters: ["grand_dad".into(), "hh".into()],
+            stage: None,
+            palettes: [0, 0],
+            side: 0,
+        }
+    }
+
+    fn inputs(n: u32) -> [Inputs; 2] {
+        let direction = if n.is_multiple_of(2) {
+            Direction::D6
+        } else {
+            Direction::D4
+        };
+        let buttons = if n.is_multiple_of(3) {
+            Buttons::P
+        } else {
+            Buttons::empty()
+        };
+
+        [
+            Inputs { direction, buttons },
+            Inputs {
+                direction: direction.flip(),
+                buttons,
+            },
+        ]
+    }
+
+    /// Polls the host and clients until every client has `frames` frames.
+    fn exchange(
+        host: &mut SpectatorHost,
+        clients: &mut [SpectatorClient],
+        confirmed: u32,
+        frames: usize,
+    ) {
+        let start = Instant::now();
+
+        while clients.iter().any(|c| c.inputs().len() < frames) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "timed out waiting for inputs"
+            );
+
+            host.poll(confirmed).unwrap();
+            for client in clients.iter_mut() {
+                client.poll().unwrap();
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn broadcast_to_many() {
+        let mut clients = [
+            SpectatorClient::bind("127.0.0.1:0", handshake()).unwrap(),
+            SpectatorClient::bind("127.0.0.1:0", handshake()).unwrap(),
+            SpectatorClient::bind("127.0.0.1:0", handshake()).unwrap(),
+        ];
+        let addrs = clients
+            .iter()
+            .map(|c| c.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let mut host = SpectatorHost::bind("127.0.0.1:0", handshake(), addrs).unwrap();
+
+        // enough frames to span several packets
+        for frame in 1..=100 {
+            host.record(frame, inputs(frame));
+        }
+
+        exchange(&mut host, &mut clients, 100, 100);
+
+        let expected = (1..=100).map(inputs).collect::<Vec<_>>();
+        for client in clients.iter() {
+            assert_eq!(client.inputs(), expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn only_confirmed_frames() {
+        let mut clients = [SpectatorClient::bind("127.0.0.1:0", handshake()).unwrap()];
+        let addr = clients[0].local_addr().unwrap();
+        let mut host = SpectatorHost::bind("127.0.0.1:0", handshake(), [addr]).unwrap();
+
+        for frame in 1..=10 {
+            host.record(frame, inputs(frame));
+        }
+
+        // roll back frames 6 and onward with different inputs
+        for frame in 6..=10 {
+            host.record(frame, inputs(frame + 1));
+        }
+
+        exchange(&mut host, &mut clients, 5, 5);
+
+        // give the host a chance to send anything it shouldn't
+        thread::sleep(Duration::from_millis(20));
+        host.poll(5).unwrap();
+        clients[0].poll().unwrap();
+        assert_eq!(clients[0].inputs().len(), 5);
+
+        exchange(&mut host, &mut clients, 10, 10);
+
+        assert_eq!(clients[0].inputs()[4], inputs(5));
+        assert_eq!(clients[0].inputs()[5], inputs(7));
+        assert_eq!(clients[0].inputs()[9], inputs(11));
+    }
+
+    #[test]
+    fn handshake_mismatch() {
+        let mut local = handshake();
+        local.digest += 1;
+
+        let mut client = SpectatorClient::bind("127.0.0.1:0", local).unwrap();
+        let addr = client.local_addr().unwrap();
+        let mut host = SpectatorHost::bind("127.0.0.1:0", handshake(), [addr]).unwrap();
+
+        for frame in 1..=10 {
+            host.record(frame, inputs(frame));
+        }
+
+        let start = Instant::now();
+        let e = loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "timed out waiting for the handshake"
+            );
+
+            host.poll(10).unwrap();
+            if let Err(e) = client.poll() {
+                break e;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        assert!(format!("{:#}", e).contains("content mismatch"), "{:#}", e);
+        assert!(client.inputs().is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+//! Stages, ready to fight on.
+//!
+//! Stages are defined by a [`bftd_lib::Stage`] in a bundle and built with
+//! [`Vfs::load_stage`](crate::assets::Vfs::load_stage). The width of the
+//! stage is part of the game: it is where the walls are. The layers are only
+//! drawn.
+
+use super::{Camera, STAGE_SIZE};
+
+use crate::render::{Color, Renderer, Texture};
+
+use bftd_lib::Rect;
+use glam::f32::{Affine2, Vec2};
+
+/// The layer the backmost stage layer is drawn on, under the players.
+pub const STAGE_LAYER: i32 = -100;
+
+/// A stage.
+#[derive(Clone, Debug)]
+pub struct Stage {
+    width: f32,
+    layers: Vec<Layer>,
+    digest: u64,
+}
+
+/// A background layer of a [`Stage`].
+#[derive(Clone, Debug)]
+pub struct Layer {
+    /// The texture of the layer.
+    pub texture: Texture,
+    /// Where the layer is in the world, when the camera is centered on the
+    /// middle of the stage.
+    pub rect: Rect,
+    /// How much the layer moves as the camera does. See
+    /// [`bftd_lib::stage::Layer::parallax`].
+    pub parallax: f32,
+}
+
+impl Stage {
+    /// Creates a new `Stage` of the given width, with `layers` back to
+    /// front.
+    pub fn new(width: f32, layers: Vec<Layer>) -> Stage {
+        Stage {
+            width,
+            layers,
+            digest: 0,
+        }
+    }
+
+    /// An empty stage [`STAGE_SIZE`] wide, for when no stage is picked.
+    pub fn void() -> Stage {
+        Stage::new(STAGE_SIZE, Vec::new())
+    }
+
+    /// Sets the digest of the stage. See [`Stage::digest`].
+    pub fn with_digest(self, digest: u64) -> Stage {
+        Stage { digest, ..self }
+    }
+
+    /// The width of the stage.
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    /// How far a player's origin can be from the middle of the stage.
+    pub fn wall(&self) -> f32 {
+        (self.width / 2. - super::WALL_MARGIN).max(0.)
+    }
+
+    /// A digest of the stage definition.
+    ///
+    /// Peers check this along with the characters, since the width of the
+    /// stage changes how a match plays out.
+    pub fn digest(&self) -> u64 {
+        self.digest
+    }
+
+    /// The layers of the stage, back to front.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Draws the layers of the stage as seen by `camera`, under the players.
+    pub fn draw(&self, camera: &Camera, cx: &mut Renderer) {
+        let layer = cx.layer();
+
+        for (i, stage_layer) in self.layers.iter().enumerate() {
+            let rect = &stage_layer.rect;
+            let center = rect.center() + camera.center() * (1. - stage_layer.parallax);
+            let transform = Affine2::from_translation(center)
+                * Affine2::from_scale(Vec2::new(rect.width(), rect.height()));
+
+            cx.set_layer(STAGE_LAYER + i as i32);
+            cx.draw_texture(
+                &stage_layer.texture,
+                Rect::new_wh(0., 0., 1., 1.),
+                transform,
+                Color::WHITE,
+            );
+        }
+
+        cx.set_layer(layer);
+    }
+}
+
+impl Default for Stage {
+    fn default() -> Stage {
+        Stage::void()
+    }
+}
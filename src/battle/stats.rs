@@ -0,0 +1,144 @@
+//! Network statistics for a [`NetBattle`](super::NetBattle).
+
+use crate::input::Inputs;
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// How many frames of inputs are remembered to detect prediction misses.
+const HISTORY_LEN: usize = 32;
+
+/// Network statistics of a [`NetBattle`](super::NetBattle).
+///
+/// These are mostly useful for tuning frame delay.
+#[derive(Clone, Debug, Default)]
+pub struct NetStats {
+    /// The round-trip time to the remote peer.
+    pub ping: Duration,
+    /// The outgoing bandwidth, in kilobits per second.
+    pub kbps_sent: u32,
+    /// How many frames the local peer is behind the remote peer.
+    pub local_frames_behind: i32,
+    /// How many frames the remote peer is behind the local peer.
+    pub remote_frames_behind: i32,
+    /// How many frames were stalled by the last time sync.
+    pub time_sync_frames: u8,
+    /// The frame delay in use.
+    pub frame_delay: u32,
+
+    /// How many rollbacks happened.
+    pub rollbacks: u32,
+    /// How many frames the last rollback rewound.
+    pub last_rollback_depth: u32,
+    /// The most frames a single rollback rewound.
+    pub max_rollback_depth: u32,
+    /// How many frames were simulated with inputs that turned out wrong.
+    pub prediction_misses: u32,
+
+    /// How many packets from the remote peer were lost.
+    pub packets_lost: u64,
+    /// The ratio of packets from the remote peer that were lost.
+    pub packet_loss: f32,
+
+    // the inputs used for the last couple of frames, newest last
+    history: VecDeque<(u32, [Inputs; 2])>,
+}
+
+impl NetStats {
+    /// Records a frame being simulated with `inputs`.
+    ///
+    /// If the frame was simulated before with different inputs, the inputs
+    /// used the first time were mispredicted.
+    pub fn record_frame(&mut self, frame: u32, inputs: [Inputs; 2]) {
+        if let Some((_, old)) = self.history.iter_mut().find(|(f, _)| *f == frame) {
+            // the frame is being simulated again after a rollback
+            if *old != inputs {
+                self.prediction_misses += 1;
+                *old = inputs;
+            }
+        } else {
+            self.history.push_back((frame, inputs));
+
+            while self.history.len() > HISTORY_LEN {
+                self.history.pop_front();
+            }
+        }
+    }
+
+    /// Records a rollback from frame `from` to frame `to`.
+    pub fn record_rollback(&mut self, from: u32, to: u32) {
+        let depth = from.saturating_sub(to);
+
+        self.rollbacks += 1;
+        self.last_rollback_depth = depth;
+        self.max_rollback_depth = self.max_rollback_depth.max(depth);
+    }
+}
+
+impl Display for NetStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ping={}ms delay={} kbps={} behind={}/{} sync={} rollbacks={} depth={}/{} misses={} loss={:.1}% ({})",
+            self.ping.as_millis(),
+            self.frame_delay,
+            self.kbps_sent,
+            self.local_frames_behind,
+            self.remote_frames_behind,
+            self.time_sync_frames,
+            self.rollbacks,
+            self.last_rollback_depth,
+            self.max_rollback_depth,
+            self.prediction_misses,
+            self.packet_loss * 100.,
+            self.packets_lost,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::input::Direction;
+
+    #[test]
+    fn prediction_misses() {
+        let neutral = [Inputs::default(); 2];
+        let walking = [
+            Inputs::default(),
+            Inputs {
+                direction: Direction::D6,
+                ..Default::default()
+            },
+        ];
+
+        let mut stats = NetStats::default();
+
+        for frame in 1..=10 {
+            stats.record_frame(frame, neutral);
+        }
+
+        // the remote actually started walking on frame 8
+        stats.record_rollback(10, 7);
+        for frame in 8..=10 {
+            stats.record_frame(frame, walking);
+        }
+        stats.record_frame(11, walking);
+
+        assert_eq!(stats.rollbacks, 1);
+        assert_eq!(stats.last_rollback_depth, 3);
+        assert_eq!(stats.prediction_misses, 3);
+
+        // simulating the same frames again with the same inputs is no miss
+        stats.record_rollback(11, 9);
+        for frame in 10..=11 {
+            stats.record_frame(frame, walking);
+        }
+
+        assert_eq!(stats.rollbacks, 2);
+        assert_eq!(stats.max_rollback_depth, 3);
+        assert_eq!(stats.prediction_misses, 3);
+    }
+}
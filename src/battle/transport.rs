@@ -0,0 +1,447 @@
+//! Instrumentation for the transport under a [`NetBattle`](super::NetBattle).
+//!
+//! Each remote peer's connection is relayed through a pair of tasks before it
+//! reaches [`backroll`]. The relay tags every outgoing packet with a sequence
+//! number and checks the sequence numbers of incoming packets, which is how
+//! packet loss is measured; [`backroll`] does not report it. Relayed packets
+//! also start with a tag, so stray packets on the same port, like late
+//! handshakes, are dropped instead of being taken for a sequence number.
+//!
+//! The relay can also put outgoing packets through simulated network
+//! [`Conditions`], so rollback can be tested with two instances on loopback.
+
+use backroll_transport::Peer;
+use bevy_tasks::TaskPool;
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The tag every relayed packet starts with. The last byte is the version of
+/// the relay's header.
+const TAG: [u8; 4] = *b"bft\x01";
+
+/// The size of the relay's header, the tag then the sequence number.
+const HEADER_SIZE: usize = TAG.len() + 4;
+
+/// The most packets that can go missing in a row before the sequence is
+/// thought to have been reset, rather than the packets lost.
+const MAX_GAP: u32 = 1 << 12;
+
+/// How much later than the others a reordered packet is sent.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Simulated network conditions.
+///
+/// Conditions only apply to the packets an instance sends, so both instances
+/// should be given the same conditions to simulate a symmetric connection.
+#[derive(Clone, Debug, Default)]
+pub struct Conditions {
+    /// The latency added to every packet.
+    pub latency: Duration,
+    /// The most latency randomly added to or taken from `latency`.
+    pub jitter: Duration,
+    /// The chance of a packet being dropped, from `0` to `1`.
+    pub loss: f32,
+    /// The chance of a packet being held back until after the packets sent
+    /// after it, from `0` to `1`.
+    pub reorder: f32,
+    /// The seed for the random number generator. If `None`, the seed is taken
+    /// from the time.
+    pub seed: Option<u64>,
+}
+
+/// Counters for a relayed connection.
+#[derive(Default)]
+pub struct Link {
+    sent: AtomicU64,
+    received: AtomicU64,
+    lost: AtomicU64,
+}
+
+impl Link {
+    /// How many packets were sent to the peer.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// How many packets were received from the peer.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// How many packets from the peer never arrived.
+    pub fn lost(&self) -> u64 {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    /// The ratio of packets from the peer that never arrived, from `0` to `1`.
+    pub fn loss(&self) -> f32 {
+        let lost = self.lost() as f32;
+        let total = lost + self.received() as f32;
+
+        if total > 0. {
+            lost / total
+        } else {
+            0.
+        }
+    }
+
+    /// Counts a packet received with the sequence number `seq`, where
+    /// `expected` is the sequence number of the packet that should come next.
+    fn track(&self, expected: &mut u32, seq: u32) {
+        let gap = seq.wrapping_sub(*expected);
+
+        if gap < MAX_GAP {
+            // everything between what we expected and this never arrived, at
+            // least not yet
+            self.lost.fetch_add(gap as u64, Ordering::Relaxed);
+            *expected = seq.wrapping_add(1);
+        } else if gap > u32::MAX - MAX_GAP {
+            // a late packet, it wasn't lost after all
+            if self.lost() > 0 {
+                self.lost.fetch_sub(1, Ordering::Relaxed);
+            }
+        } else {
+            warn!("sequence jumped to {}, starting over", seq);
+            *expected = seq.wrapping_add(1);
+        }
+
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Relays a connection, returning the end to give to [`backroll`].
+///
+/// If `conditions` are given, outgoing packets are sent through them.
+pub fn relay(pool: &TaskPool, remote: Peer, conditions: Option<&Conditions>) -> (Peer, Arc<Link>) {
+    let (peer, relayed) = Peer::create_unbounded_pair();
+    let link = Arc::new(Link::default());
+    let outgoing = conditions.map(|c| condition(c.clone(), remote.clone()));
+
+    // outgoing
+    {
+        let (relayed, remote, link) = (relayed.clone(), remote.clone(), link.clone());
+
+        pool.spawn(async move {
+            let mut seq = 0u32;
+
+            while let Ok(msg) = relayed.recv().await {
+                let mut packet = Vec::with_capacity(msg.len() + HEADER_SIZE);
+                packet.extend_from_slice(&TAG);
+                packet.extend_from_slice(&seq.to_le_bytes());
+                packet.extend_from_slice(&msg);
+                let packet = packet.into_boxed_slice();
+
+                let sent = match &outgoing {
+                    Some(outgoing) => outgoing.send(packet).is_ok(),
+                    None => remote.send(packet).await.is_ok(),
+                };
+
+                if !sent {
+                    break;
+                }
+
+                seq = seq.wrapping_add(1);
+                link.sent.fetch_add(1, Ordering::Relaxed);
+            }
+
+            remote.disconnect();
+        })
+        .detach();
+    }
+
+    // incoming
+    {
+        let link = link.clone();
+
+        pool.spawn(async move {
+            let mut expected = 0u32;
+
+            while let Ok(packet) = remote.recv().await {
+                let seq = match sequence(&packet) {
+                    Some(seq) => seq,
+                    None => {
+                        debug!("dropping stray packet of {} bytes", packet.len());
+                        continue;
+                    }
+                };
+
+                link.track(&mut expected, seq);
+
+                if relayed.send(packet[HEADER_SIZE..].into()).await.is_err() {
+                    break;
+                }
+            }
+
+            relayed.disconnect();
+        })
+        .detach();
+    }
+
+    (peer, link)
+}
+
+/// Reads the sequence number of a relayed packet, or `None` if it wasn't sent
+/// by a relay.
+fn sequence(packet: &[u8]) -> Option<u32> {
+    if packet.len() < HEADER_SIZE || packet[..TAG.len()] != TAG {
+        return None;
+    }
+
+    let seq = &packet[TAG.len()..HEADER_SIZE];
+    Some(u32::from_le_bytes([seq[0], seq[1], seq[2], seq[3]]))
+}
+
+/// Spawns a thread that sends packets to `remote` under `conditions`.
+fn condition(conditions: Conditions, remote: Peer) -> mpsc::Sender<Box<[u8]>> {
+    let (tx, rx) = mpsc::channel::<Box<[u8]>>();
+
+    let seed = conditions.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+    });
+
+    info!(
+        "simulating network conditions {:?} with seed {}",
+        conditions, seed
+    );
+
+    thread::spawn(move || {
+        let mut rng = Rng::new(seed);
+        let mut queue = BinaryHeap::new();
+        let mut seq = 0u64;
+
+        loop {
+            // wait for a packet, or for the next packet to be due
+            let packet = match queue.peek() {
+                Some(Reverse(Delayed { at, .. })) => {
+                    match rx.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                        Ok(packet) => Some(packet),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(packet) => Some(packet),
+                    Err(_) => break,
+                },
+            };
+
+            if let Some(packet) = packet {
+                if rng.chance(conditions.loss) {
+                    continue;
+                }
+
+                let jitter = conditions.jitter.mul_f32(rng.next_f32() * 2.);
+                let mut delay = (conditions.latency + jitter).saturating_sub(conditions.jitter);
+
+                if rng.chance(conditions.reorder) {
+                    delay += REORDER_DELAY;
+                }
+
+                queue.push(Reverse(Delayed {
+                    at: Instant::now() + delay,
+                    seq,
+                    packet,
+                }));
+                seq += 1;
+            }
+
+            // send everything that is due
+            while let Some(Reverse(Delayed { at, .. })) = queue.peek() {
+                if *at > Instant::now() {
+                    break;
+                }
+
+                let Reverse(delayed) = queue.pop().unwrap();
+                if remote.try_send(delayed.packet).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Delayed {
+    at: Instant,
+    // keeps packets due at the same time in order
+    seq: u64,
+    packet: Box<[u8]>,
+}
+
+/// A tiny xorshift random number generator.
+///
+/// The simulated conditions don't need anything better, and this keeps runs
+/// with the same seed reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // xorshift gets stuck on zero
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A random float from `0` to `1`.
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, p: f32) -> bool {
+        p > 0. && self.next_f32() < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u32) -> Vec<u8> {
+        let mut packet = TAG.to_vec();
+        packet.extend_from_slice(&seq.to_le_bytes());
+        packet.extend_from_slice(b"input");
+        packet
+    }
+
+    #[test]
+    fn stray_packets() {
+        assert_eq!(sequence(&packet(7)), Some(7));
+
+        // a handshake ping, and a packet too short to have a header
+        assert_eq!(sequence(b"Ping(stamp:3)"), None);
+        assert_eq!(sequence(&TAG), None);
+    }
+
+    #[test]
+    fn loss() {
+        let link = Link::default();
+        let mut expected = 0;
+
+        for seq in [0, 1, 3, 4] {
+            link.track(&mut expected, seq);
+        }
+        assert_eq!((link.received(), link.lost()), (4, 1));
+
+        // the missing packet shows up late
+        link.track(&mut expected, 2);
+        assert_eq!((link.received(), link.lost()), (5, 0));
+        assert_eq!(expected, 5);
+
+        // a jump too big to be loss doesn't count as loss
+        link.track(&mut expected, u32::MAX / 2);
+        assert_eq!((link.received(), link.lost()), (6, 0));
+        assert_eq!(expected, u32::MAX / 2 + 1);
+    }
+
+    /// Sends `count` numbered packets under `conditions`, returning the
+    /// numbers of the packets that made it, in the order they arrived.
+    fn simulate(conditions: Conditions, count: u32) -> Vec<u32> {
+        let (local, remote) = Peer::create_unbounded_pair();
+        let tx = condition(conditions.clone(), local);
+
+        for i in 0..count {
+            tx.send(i.to_le_bytes().into()).unwrap();
+        }
+
+        // wait for the packets held back to be sent too
+        thread::sleep(conditions.latency + conditions.jitter + REORDER_DELAY * 2);
+        drop(tx);
+
+        let mut received = Vec::new();
+        while let Ok(packet) = pollster::block_on(remote.recv()) {
+            received.push(u32::from_le_bytes(packet[..4].try_into().unwrap()));
+        }
+
+        received
+    }
+
+    #[test]
+    fn rng() {
+        let (mut a, mut b) = (Rng::new(3), Rng::new(3));
+        assert!((0..100).all(|_| a.next_u64() == b.next_u64()));
+
+        assert_ne!(Rng::new(3).next_u64(), Rng::new(4).next_u64());
+        let floats = (0..1000).map(|_| a.next_f32()).collect::<Vec<_>>();
+        assert!(floats.iter().all(|f| (0. ..1.).contains(f)));
+
+        assert!(!a.chance(0.));
+        assert!(a.chance(1.));
+    }
+
+    #[test]
+    fn simulated_loss() {
+        let conditions = Conditions {
+            loss: 0.25,
+            seed: Some(7),
+            ..Default::default()
+        };
+
+        let received = simulate(conditions.clone(), 2000);
+        let lost = 2000 - received.len();
+        assert!((400..600).contains(&lost), "lost {} packets", lost);
+
+        // without reordering, the packets that make it stay in order
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+
+        // the same seed loses the same packets
+        assert_eq!(simulate(conditions, 2000), received);
+    }
+
+    #[test]
+    fn simulated_latency() {
+        let latency = Duration::from_millis(20);
+        let (local, remote) = Peer::create_unbounded_pair();
+        let tx = condition(
+            Conditions {
+                latency,
+                seed: Some(7),
+                ..Default::default()
+            },
+            local,
+        );
+
+        for i in 0..5u32 {
+            let sent = Instant::now();
+            tx.send(i.to_le_bytes().into()).unwrap();
+            pollster::block_on(remote.recv()).unwrap();
+
+            assert!(sent.elapsed() >= latency);
+        }
+    }
+
+    #[test]
+    fn simulated_reorder() {
+        let received = simulate(
+            Conditions {
+                reorder: 0.3,
+                seed: Some(7),
+                ..Default::default()
+            },
+            200,
+        );
+
+        // nothing is lost, but some packets come after the ones sent after
+        // them
+        assert!(received.windows(2).any(|w| w[0] > w[1]));
+
+        let mut sorted = received.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..200).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,28 @@
+//! The `bftd` lobby server.
+
+use anyhow::Error;
+
+use clap::{Arg, Command};
+
+use bftd::lobby::{Server, DEFAULT_PORT};
+
+pub fn main() -> Result<(), Error> {
+    env_logger::init();
+
+    let default_bind = format!("0.0.0.0:{}", DEFAULT_PORT);
+
+    let m = Command::new("bftd-lobby")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Lobby server for Battle for the Domain")
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .default_value(&default_bind)
+                .help("The address to listen on"),
+        )
+        .get_matches();
+
+    let mut server = Server::bind(m.value_of("bind").unwrap())?;
+    server.run()
+}
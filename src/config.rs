@@ -2,9 +2,72 @@
 
 use clap::{Command, Arg};
 
+use crate::battle::transport::Conditions;
+use crate::battle::FrameDelay;
+use crate::lobby::RoomId;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
 /// Executable arguments.
 pub struct Args {
+    /// A command to run instead of the game, if any.
+    pub command: Option<Subcommand>,
     pub netmode: u32,
+    /// Mod bundles to mount over the core bundle, lowest priority first.
+    pub mods: Vec<PathBuf>,
+    /// If the bundle supplying each asset should be logged.
+    pub list_assets: bool,
+    /// The path of the stage to fight on, if not the first one provided.
+    pub stage: Option<String>,
+    /// The palette each player is drawn in, left then right.
+    pub palettes: [usize; 2],
+    /// If characters should be reloaded when their files change.
+    pub hot_reload: bool,
+    /// If hitboxes, hurtboxes, pushboxes and origins should be drawn from the
+    /// start. This can be toggled in game with F3.
+    pub debug_boxes: bool,
+    /// Addresses of spectators to broadcast to.
+    pub spectators: Vec<SocketAddr>,
+    /// The address to spectate a battle from, if spectating.
+    pub spectate: Option<SocketAddr>,
+    /// How many frames behind a spectated battle should be.
+    pub spectate_delay: u32,
+    /// If network statistics should be shown.
+    pub net_stats: bool,
+    /// How much frame delay networked battles use.
+    pub frame_delay: FrameDelay,
+    /// Simulated network conditions, if any were asked for.
+    pub conditions: Option<Conditions>,
+    /// The lobby server to find an opponent through, if any.
+    pub lobby: Option<SocketAddr>,
+    /// The address to play from when using a lobby.
+    pub bind: SocketAddr,
+    /// The name to register with the lobby.
+    pub name: String,
+    /// The name of the room to open in the lobby.
+    pub room: String,
+    /// The lobby room to join, instead of opening one.
+    pub join: Option<RoomId>,
+}
+
+/// A command run from the command line instead of the game.
+pub enum Subcommand {
+    /// Packs the bundle in a directory into a single file.
+    Pack {
+        /// The directory of the bundle.
+        bundle: PathBuf,
+        /// The pack file to write.
+        out: PathBuf,
+        /// Whether to pack the loose textures of characters into atlases.
+        atlas: bool,
+    },
+    /// Checks bundles for problems.
+    Validate {
+        /// The bundles to mount, lowest priority first.
+        bundles: Vec<PathBuf>,
+    },
 }
 
 impl Args {
@@ -14,16 +77,264 @@ impl Args {
             .author(env!("CARGO_PKG_AUTHORS"))
             .version(env!("CARGO_PKG_VERSION"))
             .about(env!("CARGO_PKG_DESCRIPTION"))
+            .subcommand(
+                Command::new("pack")
+                    .about("Packs a bundle directory into a single file")
+                    .arg(
+                        Arg::new("bundle")
+                            .required(true)
+                            .help("The directory of the bundle to pack")
+                    )
+                    .arg(
+                        Arg::new("out")
+                            .required(true)
+                            .help("The pack file to write")
+                    )
+                    .arg(
+                        Arg::new("atlas")
+                            .long("atlas")
+                            .help("Packs the loose textures of each character into an atlas")
+                    )
+            )
+            .subcommand(
+                Command::new("validate")
+                    .about("Checks bundles for broken characters, scripts and textures")
+                    .arg(
+                        Arg::new("bundle")
+                            .required(true)
+                            .multiple_values(true)
+                            .help("The bundles to check; mods go after the bundles they depend on")
+                    )
+            )
             .arg(
                 Arg::new("netmode")
                     .long("netmode")
                     .default_value("0")
+                    .possible_values(["0", "1", "2", "3"])
+                    .help("0 or 1 to play left or right online, 2 to spectate on 127.0.0.1:19193, 3 to play locally")
+            )
+            .arg(
+                Arg::new("mod")
+                    .long("mod")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .help("Mounts the bundle at this path over the core bundle; later mods take priority")
+            )
+            .arg(
+                Arg::new("list-assets")
+                    .long("list-assets")
+                    .help("Logs which bundle supplies each asset")
+            )
+            .arg(
+                Arg::new("stage")
+                    .long("stage")
+                    .takes_value(true)
+                    .help("The path of the stage to fight on; both peers have to pick the same one")
+            )
+            .arg(
+                Arg::new("palettes")
+                    .long("palettes")
+                    .number_of_values(2)
+                    .default_values(&["0", "0"])
+                    .validator(|v| v.parse::<usize>())
+                    .help("The palettes of the left and right players; both peers have to pick the same ones")
+            )
+            .arg(
+                Arg::new("debug-boxes")
+                    .long("debug-boxes")
+                    .help("Draws hitboxes, hurtboxes, pushboxes and origins; toggle with F3")
+            )
+            .arg(
+                Arg::new("hot-reload")
+                    .long("hot-reload")
+                    .help("Reloads characters when their files change; only works in local battles")
+            )
+            .arg(
+                Arg::new("spectator")
+                    .long("spectator")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .validator(|v| v.parse::<SocketAddr>())
+                    .help("Broadcasts the battle to a spectator at this address")
+            )
+            .arg(
+                Arg::new("spectate")
+                    .long("spectate")
+                    .takes_value(true)
+                    .validator(|v| v.parse::<SocketAddr>())
+                    .help("Spectates a battle, waiting for its host to broadcast to this address")
+            )
+            .arg(
+                Arg::new("spectate-delay")
+                    .long("spectate-delay")
+                    .default_value("30")
+                    .validator(|v| v.parse::<u32>())
+                    .help("How many frames behind a spectated battle is played")
+            )
+            .arg(
+                Arg::new("net-stats")
+                    .long("net-stats")
+                    .help("Shows network statistics over the battle")
+            )
+            .arg(
+                Arg::new("frame-delay")
+                    .long("frame-delay")
+                    .default_value("0")
+                    .validator(|v| v.parse::<FrameDelay>())
+                    .help("Frames of input delay online: a number, auto to pick from ping, or adaptive to also repick between rounds")
+            )
+            .arg(
+                Arg::new("sim-latency")
+                    .long("sim-latency")
+                    .takes_value(true)
+                    .validator(|v| v.parse::<u64>())
+                    .help("Simulates this much latency on sent packets, in milliseconds")
+            )
+            .arg(
+                Arg::new("sim-jitter")
+                    .long("sim-jitter")
+                    .takes_value(true)
+                    .validator(|v| v.parse::<u64>())
+                    .help("Simulates up to this much jitter on sent packets, in milliseconds")
+            )
+            .arg(
+                Arg::new("sim-loss")
+                    .long("sim-loss")
+                    .takes_value(true)
+                    .validator(percentage)
+                    .help("Simulates losing this percentage of sent packets")
+            )
+            .arg(
+                Arg::new("sim-reorder")
+                    .long("sim-reorder")
+                    .takes_value(true)
+                    .validator(percentage)
+                    .help("Simulates reordering this percentage of sent packets")
+            )
+            .arg(
+                Arg::new("sim-seed")
+                    .long("sim-seed")
+                    .takes_value(true)
+                    .validator(|v| v.parse::<u64>())
+                    .help("Seeds the network simulation, for reproducible runs")
+            )
+            .arg(
+                Arg::new("lobby")
+                    .long("lobby")
+                    .takes_value(true)
+                    .validator(|v| v.parse::<SocketAddr>())
+                    .help("Finds an opponent through the lobby server at this address")
+            )
+            .arg(
+                Arg::new("bind")
+                    .long("bind")
+                    .default_value("0.0.0.0:0")
+                    .validator(|v| v.parse::<SocketAddr>())
+                    .help("The address to play from when using a lobby")
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .default_value("player")
+                    .help("The name to register with the lobby")
+            )
+            .arg(
+                Arg::new("room")
+                    .long("room")
+                    .default_value("bftd")
+                    .help("The name of the room to open in the lobby")
+            )
+            .arg(
+                Arg::new("join")
+                    .long("join")
+                    .takes_value(true)
+                    .validator(|v| v.parse::<RoomId>())
+                    .help("Joins the lobby room with this id instead of opening one")
             )
             .get_matches();
 
+        let simulated = [
+            "sim-latency",
+            "sim-jitter",
+            "sim-loss",
+            "sim-reorder",
+            "sim-seed",
+        ]
+        .iter()
+        .any(|name| m.is_present(name));
+
+        let conditions = if simulated {
+            let millis = |name: &str| {
+                Duration::from_millis(m.value_of(name).map_or(0, |v| v.parse().unwrap()))
+            };
+            let percent = |name: &str| {
+                m.value_of(name)
+                    .map_or(0., |v| v.parse::<f32>().unwrap() / 100.)
+            };
+
+            Some(Conditions {
+                latency: millis("sim-latency"),
+                jitter: millis("sim-jitter"),
+                loss: percent("sim-loss"),
+                reorder: percent("sim-reorder"),
+                seed: m.value_of("sim-seed").map(|v| v.parse().unwrap()),
+            })
+        } else {
+            None
+        };
+
+        let command = match m.subcommand() {
+            Some(("pack", m)) => Some(Subcommand::Pack {
+                bundle: m.value_of("bundle").unwrap().into(),
+                out: m.value_of("out").unwrap().into(),
+                atlas: m.is_present("atlas"),
+            }),
+            Some(("validate", m)) => Some(Subcommand::Validate {
+                bundles: m.values_of("bundle").unwrap().map(PathBuf::from).collect(),
+            }),
+            _ => None,
+        };
+
         Args {
+            command,
             netmode: m.value_of("netmode").unwrap().parse().unwrap(),
+            mods: m
+                .values_of("mod")
+                .map(|v| v.map(PathBuf::from).collect())
+                .unwrap_or_default(),
+            list_assets: m.is_present("list-assets"),
+            stage: m.value_of("stage").map(str::to_owned),
+            palettes: {
+                let mut palettes = m.values_of("palettes").unwrap().map(|v| v.parse().unwrap());
+                [palettes.next().unwrap(), palettes.next().unwrap()]
+            },
+            hot_reload: m.is_present("hot-reload"),
+            debug_boxes: m.is_present("debug-boxes"),
+            spectators: m
+                .values_of("spectator")
+                .map(|v| v.map(|s| s.parse().unwrap()).collect())
+                .unwrap_or_default(),
+            spectate: m.value_of("spectate").map(|v| v.parse().unwrap()),
+            spectate_delay: m.value_of("spectate-delay").unwrap().parse().unwrap(),
+            net_stats: m.is_present("net-stats"),
+            frame_delay: m.value_of("frame-delay").unwrap().parse().unwrap(),
+            conditions,
+            lobby: m.value_of("lobby").map(|v| v.parse().unwrap()),
+            bind: m.value_of("bind").unwrap().parse().unwrap(),
+            name: m.value_of("name").unwrap().to_owned(),
+            room: m.value_of("room").unwrap().to_owned(),
+            join: m.value_of("join").map(|v| v.parse().unwrap()),
         }
     }
 }
 
+/// Checks that an argument is a percentage, from `0` to `100`.
+fn percentage(v: &str) -> Result<f32, String> {
+    let percent = v.parse::<f32>().map_err(|e| e.to_string())?;
+
+    if (0. ..=100.).contains(&percent) {
+        Ok(percent)
+    } else {
+        Err(format!("{} is not between 0 and 100", percent))
+    }
+}
@@ -59,6 +59,28 @@ impl Buffer {
     pub fn last(&self) -> Inputs {
         *self.0.read().unwrap().last().unwrap()
     }
+
+    /// The inputs being held on the frame before the last frame.
+    ///
+    /// Returns the default inputs if there is no such frame.
+    pub fn previous(&self) -> Inputs {
+        let inputs = self.0.read().unwrap();
+
+        match inputs.len() {
+            0 | 1 => Inputs::default(),
+            len => inputs[len - 2],
+        }
+    }
+
+    /// The buttons that started being held on the last frame.
+    pub fn pressed(&self) -> Buttons {
+        self.buttons() & !self.previous().buttons
+    }
+
+    /// Checks if the direction changed to `direction` on the last frame.
+    pub fn tapped(&self, direction: Direction) -> bool {
+        self.direction() == direction && self.previous().direction != direction
+    }
 }
 
 impl Debug for Buffer {
@@ -78,7 +100,7 @@ impl Hash for Buffer {
 }
 
 /// A single frame of inputs.
-#[derive(Clone, Copy, Default, PartialEq, Pod, Eq, Hash, Zeroable)]
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Pod, Eq, Serialize, Hash, Zeroable)]
 #[repr(C)]
 pub struct Inputs {
     /// The direction.
@@ -1,7 +1,5 @@
 //! # `bftd`
 
-#![feature(maybe_uninit_uninit_array, maybe_uninit_array_assume_init)]
-
 #[macro_use]
 extern crate anyhow;
 
@@ -12,20 +10,47 @@ pub mod assets;
 pub mod battle;
 pub mod config;
 pub mod input;
+pub mod lobby;
 pub mod render;
 pub mod timer;
 
+use assets::{Bundle, Loader, Vfs};
+use battle::fsm::Fsm;
+use battle::handshake::{self, Handshake};
+use battle::{Battle, Hud, NetPlayer, Stage};
 use input::Handle;
-use render::Renderer;
+use render::{Drawable, Font, Renderer, Text};
 
 use anyhow::Error;
+use bevy_tasks::TaskPool;
+
+use glam::f32::{Affine2, Vec2};
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for an opponent in a lobby room.
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often bundles are checked for changes when hot reloading.
+const RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The path of the font messages are shown in.
+const FONT_PATH: &str = "/fonts/small.bdf";
+
+/// The height of a line of a message, where the screen is `1` tall.
+const MESSAGE_HEIGHT: f32 = 0.04;
 
 /// Global game context.
 pub struct Context {
     /// The render context.
     pub render: render::Context,
     /// The scripting engine used to run scripts in-battle.
-    pub script: battle::script::Engine,
+    ///
+    /// This is shared with the tasks that compile scripts while loading.
+    pub script: Arc<battle::script::Engine>,
     /// The input handler.
     pub input: input::Sampler,
     /// A frame limiter.
@@ -38,49 +63,472 @@ pub struct Context {
 
 /// The game.
 pub struct Game {
-    core_bundle: assets::Bundle,
-    battle: battle::NetBattle,
+    assets: Vfs,
+    // the path of each character in the roster
+    characters: Vec<String>,
+    // the path of the stage picked, if there are any
+    stage: Option<String>,
+    screen: Screen,
+    reloaded: Instant,
+    font: Option<Arc<Font>>,
+    // if network statistics are drawn over the battle
+    show_net_stats: bool,
+}
+
+/// What the game is showing.
+enum Screen {
+    /// The characters are loading.
+    Loading(Loader),
+    /// The opponent is being looked for and connected to.
+    Connecting(Connecting),
+    /// A battle is being played.
+    Battle(Box<dyn Battle>),
 }
 
 impl Game {
     /// Creates a new game.
     pub fn new(cx: &mut Context) -> Result<Game, Error> {
-        let mut core_bundle = assets::Bundle::new("assets/")?;
+        let mut assets = Vfs::new();
 
-        let gdfsm = core_bundle.load_character(cx, "/characters/grand_dad.ron")?;
-        let hhfsm = core_bundle.load_character(cx, "/characters/hh.ron")?;
+        // mods go on top of the core bundle
+        assets.mount(Bundle::new("assets/")?)?;
+        for path in cx.args.mods.iter() {
+            assets.mount(Bundle::new(path)?)?;
+        }
+        assets.check()?;
 
-        // note that arena is being made the same exact way
-        let arena = battle::Arena::new(&cx.script, gdfsm, hhfsm)?;
+        if cx.args.list_assets {
+            for (path, bundle) in assets.assets()? {
+                info!("{} <- {}", path, bundle.metadata());
+            }
+        }
 
-        let p1: std::net::SocketAddr = ([127, 0, 0, 1], 19191).into();
-        let p2: std::net::SocketAddr = ([127, 0, 0, 1], 19192).into();
+        // the roster is whatever the bundles provide
+        let characters = assets.characters();
 
-        let battle = if cx.args.netmode == 0 {
-            battle::NetBattle::new(cx, arena, p1, &[battle::NetPlayer::Local(Handle::new(0)), battle::NetPlayer::Remote(p2)])?
-        } else if cx.args.netmode == 1 {
-            battle::NetBattle::new(cx, arena, p2, &[battle::NetPlayer::Remote(p1), battle::NetPlayer::Local(Handle::new(0))])?
-        } else {
-            todo!()
+        if characters.is_empty() {
+            bail!("no mounted bundle provides any characters");
+        }
+
+        // fight on the stage asked for, or the first one there is
+        let stages = assets.stages();
+        let stage = match &cx.args.stage {
+            Some(stage) => {
+                let declared =
+                    |s: &String| s.trim_start_matches('/') == stage.trim_start_matches('/');
+                if !stages.iter().any(declared) {
+                    bail!("no mounted bundle provides the stage {}", stage);
+                }
+
+                Some(stage.clone())
+            }
+            None => stages.first().cloned(),
+        };
+
+        // the font is needed to show loading progress, so it comes first
+        let font = match assets.provider(FONT_PATH) {
+            Some(_) => assets
+                .load::<Font>(cx, FONT_PATH)
+                .map_err(|e| warn!("cannot load the font: {:?}", e))
+                .ok(),
+            None => None,
         };
 
+        // everything else is loaded in the background
+        let loader = Loader::new(
+            &cx.task_pool,
+            &assets,
+            cx.script.clone(),
+            characters.clone(),
+        );
+
         Ok(Game {
-            core_bundle,
-            battle,
-            //battle: battle::LocalBattle::new(arena, Handle::new(0), Handle::new(1)),
+            assets,
+            characters,
+            stage,
+            screen: Screen::Loading(loader),
+            reloaded: Instant::now(),
+            font,
+            show_net_stats: cx.args.net_stats,
         })
     }
 
     /// Updates the game state.
     ///
-    /// This should be called as frequently as possible.
-    pub fn update(&mut self, cx: &mut Context) {
-        self.battle.update(cx).unwrap();
+    /// This should be called as frequently as possible. An error means the
+    /// battle cannot go on.
+    pub fn update(&mut self, cx: &mut Context) -> Result<(), Error> {
+        // reload between frames, so the battle never sees half a character
+        if cx.args.hot_reload && self.reloaded.elapsed() >= RELOAD_INTERVAL {
+            self.reloaded = Instant::now();
+            self.reload(cx);
+        }
+
+        let next = match &mut self.screen {
+            Screen::Loading(loader) => {
+                loader.poll(cx, &mut self.assets)?;
+
+                if !loader.is_done() {
+                    return Ok(());
+                }
+
+                let roster = loader.finish(cx, &mut self.assets)?;
+                info!("loaded {} characters", roster.len());
+
+                let stage = match &self.stage {
+                    Some(path) => self.assets.load_stage(cx, path)?,
+                    None => Stage::void(),
+                };
+
+                let hud = self.load_hud(cx);
+                self.start(cx, roster, stage, hud)?
+            }
+            Screen::Connecting(connecting) => match connecting.poll(cx)? {
+                Some(battle) => Screen::Battle(battle),
+                None => return Ok(()),
+            },
+            Screen::Battle(battle) => return battle.update(cx),
+        };
+
+        self.screen = next;
+
+        Ok(())
+    }
+
+    /// Loads the HUD, if any bundle has one.
+    ///
+    /// A HUD that fails to load is logged and left out, since the battle can
+    /// go on without it.
+    fn load_hud(&mut self, cx: &mut Context) -> Option<Hud> {
+        self.assets.provider(battle::hud::LAYOUT_PATH)?;
+
+        match self.assets.load_hud(cx, battle::hud::LAYOUT_PATH) {
+            Ok(hud) => Some(hud),
+            Err(e) => {
+                warn!("cannot load the HUD: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Starts a battle with the loaded `roster`, on `stage`.
+    ///
+    /// A networked battle first has to find and connect to its opponent,
+    /// which happens on the task pool.
+    fn start(
+        &self,
+        cx: &mut Context,
+        roster: Vec<Fsm>,
+        stage: Stage,
+        hud: Option<Hud>,
+    ) -> Result<Screen, Error> {
+        let picks = [0, 1 % self.characters.len()];
+        let palettes = cx.args.palettes;
+        let mut series = battle::Series::new(&cx.script, roster, picks)?
+            .with_stage(stage)
+            .with_palettes(palettes);
+
+        if let Some(hud) = hud {
+            series = series.with_hud(hud);
+        }
+
+        // make sure the peer has built the arena the same exact way
+        let mut local = Handshake {
+            bundles: self.assets.metadata(),
+            digest: series.digest(),
+            characters: picks.map(|i| self.characters[i].clone()),
+            stage: self.stage.clone(),
+            palettes,
+            side: 0,
+        };
+
+        let p1: SocketAddr = ([127, 0, 0, 1], 19191).into();
+        let p2: SocketAddr = ([127, 0, 0, 1], 19192).into();
+        let p3: SocketAddr = ([127, 0, 0, 1], 19193).into();
+
+        let spectators = cx
+            .args
+            .spectators
+            .iter()
+            .copied()
+            .map(NetPlayer::Spectator)
+            .collect();
+
+        let screen = if let Some(server) = cx.args.lobby {
+            let bind_addrs = cx.args.bind;
+            let name = cx.args.name.clone();
+            let join = cx.args.join;
+            let room = cx.args.room.clone();
+
+            Screen::Connecting(Connecting::new(
+                &cx.task_pool,
+                series,
+                spectators,
+                move || {
+                    let client = lobby::Client::connect(bind_addrs, server, &name)?;
+
+                    let opponent = match join {
+                        Some(id) => client.join(id)?,
+                        None => {
+                            let id = client.create_room(&room)?;
+                            info!("opened room {}, waiting for an opponent...", id);
+                            client.wait_for_match(LOBBY_TIMEOUT)?
+                        }
+                    };
+
+                    info!("matched with {} at {}", opponent.peer_name, opponent.peer);
+
+                    // battle from the same address the lobby knows us by
+                    let bind = client.local_addr()?;
+                    client.leave()?;
+
+                    local.side = opponent.side;
+                    let remote = handshake::exchange(
+                        bind,
+                        opponent.peer,
+                        &local,
+                        handshake::DEFAULT_TIMEOUT,
+                    )?;
+
+                    Ok(Connection {
+                        bind,
+                        peer: opponent.peer,
+                        side: opponent.side,
+                        rtt: remote.rtt,
+                        handshake: local,
+                    })
+                },
+            ))
+        } else if let Some(bind) = cx.args.spectate.or((cx.args.netmode == 2).then_some(p3)) {
+            let delay = cx.args.spectate_delay;
+            Screen::Battle(Box::new(battle::SpectateBattle::new(
+                series, local, bind, delay,
+            )?))
+        } else if cx.args.netmode == 0 || cx.args.netmode == 1 {
+            let side = cx.args.netmode as usize;
+            let (bind, peer) = if side == 0 { (p1, p2) } else { (p2, p1) };
+
+            Screen::Connecting(Connecting::new(
+                &cx.task_pool,
+                series,
+                spectators,
+                move || {
+                    local.side = side;
+                    let remote =
+                        handshake::exchange(bind, peer, &local, handshake::DEFAULT_TIMEOUT)?;
+
+                    Ok(Connection {
+                        bind,
+                        peer,
+                        side,
+                        rtt: remote.rtt,
+                        handshake: local,
+                    })
+                },
+            ))
+        } else if cx.args.netmode == 3 {
+            Screen::Battle(Box::new(battle::LocalBattle::new(
+                series,
+                Handle::new(0),
+                Handle::new(1),
+            )))
+        } else {
+            unreachable!("clap only allows netmodes 0 to 3")
+        };
+
+        Ok(screen)
+    }
+
+    /// Reloads the characters built from files that changed.
+    ///
+    /// A character that fails to load is logged and kept as it was, so a
+    /// typo in a script doesn't end the battle.
+    fn reload(&mut self, cx: &mut Context) {
+        let battle = match &mut self.screen {
+            Screen::Battle(battle) => battle,
+            Screen::Loading(_) | Screen::Connecting(_) => return,
+        };
+
+        let changed = self.assets.changes();
+
+        if changed.is_empty() {
+            return;
+        }
+
+        info!("changed: {}", changed.join(", "));
+
+        for path in self.assets.dependents(&changed) {
+            let index = match self
+                .characters
+                .iter()
+                .position(|c| c.trim_start_matches('/') == path)
+            {
+                Some(index) => index,
+                None => continue,
+            };
+
+            let result = self
+                .assets
+                .load_character(cx, &path)
+                .and_then(|fsm| battle.replace_character(&cx.script, index, fsm));
+
+            match result {
+                Ok(()) => info!("reloaded {}", path),
+                Err(e) => warn!("cannot reload {}: {:?}", path, e),
+            }
+        }
     }
 
     /// Draws the game state to the screen.
+    ///
+    /// The status of the game is shown in the bottom-left corner, and the
+    /// network statistics in the top-left corner if they were asked for.
     pub fn draw(&mut self, cx: &mut Renderer) {
-        self.battle.draw(cx).unwrap();
+        if let Screen::Battle(battle) = &mut self.screen {
+            battle.draw(cx).unwrap();
+        }
+
+        let font = match &self.font {
+            Some(font) => font,
+            None => return,
+        };
+
+        let left = -0.5 / cx.aspect_ratio() + MESSAGE_HEIGHT;
+
+        if let Some(status) = self.status() {
+            let mut text = Text::new(font.clone(), status, MESSAGE_HEIGHT);
+            text.set_transform(Affine2::from_translation(Vec2::new(
+                left,
+                -0.5 + MESSAGE_HEIGHT,
+            )));
+            draw_overlay(cx, &text);
+        }
+
+        if let (true, Some(stats)) = (self.show_net_stats, self.net_stats()) {
+            let mut text = Text::new(font.clone(), stats.to_string(), MESSAGE_HEIGHT);
+            text.set_transform(Affine2::from_translation(Vec2::new(
+                left,
+                0.5 - 2. * MESSAGE_HEIGHT,
+            )));
+            draw_overlay(cx, &text);
+        }
+    }
+
+    /// How far along loading is, if the game is still loading.
+    pub fn progress(&self) -> Option<assets::Progress> {
+        match &self.screen {
+            Screen::Loading(loader) => Some(loader.progress()),
+            Screen::Connecting(_) | Screen::Battle(_) => None,
+        }
+    }
+
+    /// How the game ended, if it is over and the application should close.
+    pub fn outcome(&self) -> Option<battle::Outcome> {
+        match &self.screen {
+            Screen::Battle(battle) => battle.outcome(),
+            Screen::Loading(_) | Screen::Connecting(_) => None,
+        }
+    }
+
+    /// A short message about the state of the game, if there is anything the
+    /// players should know about.
+    pub fn status(&self) -> Option<String> {
+        match &self.screen {
+            Screen::Loading(loader) => Some(format!("loading {}", loader.progress())),
+            Screen::Connecting(_) => Some("connecting...".into()),
+            Screen::Battle(battle) => battle.status(),
+        }
+    }
+
+    /// The network statistics of the current battle, if it is networked.
+    pub fn net_stats(&self) -> Option<&battle::NetStats> {
+        match &self.screen {
+            Screen::Battle(battle) => battle.net_stats(),
+            Screen::Loading(_) | Screen::Connecting(_) => None,
+        }
     }
 }
 
+/// Draws `text` in screen space, over everything.
+fn draw_overlay(cx: &mut Renderer, text: &Text) {
+    let (world, layer) = (cx.transform(), cx.layer());
+    cx.set_transform(Affine2::IDENTITY);
+    cx.set_layer(battle::DEBUG_LAYER);
+    text.draw(cx);
+    cx.set_transform(world);
+    cx.set_layer(layer);
+}
+
+/// A networked battle waiting on its opponent.
+struct Connecting {
+    // taken once connected
+    series: Option<battle::Series>,
+    spectators: Vec<NetPlayer>,
+    rx: Receiver<Result<Connection, Error>>,
+}
+
+/// Who a networked battle is played against, and from where.
+struct Connection {
+    bind: SocketAddr,
+    peer: SocketAddr,
+    side: usize,
+    rtt: Option<Duration>,
+    // what we told the opponent, to tell spectators the same
+    handshake: Handshake,
+}
+
+impl Connecting {
+    /// Runs `connect` on the task pool.
+    ///
+    /// Finding an opponent and exchanging handshakes blocks for as long as it
+    /// takes the opponent to show up, which shouldn't freeze the window.
+    fn new<F>(
+        pool: &TaskPool,
+        series: battle::Series,
+        spectators: Vec<NetPlayer>,
+        connect: F,
+    ) -> Connecting
+    where
+        F: FnOnce() -> Result<Connection, Error> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+
+        pool.spawn(async move {
+            // the game was closed, nobody wants this anymore
+            let _ = tx.send(connect());
+        })
+        .detach();
+
+        Connecting {
+            series: Some(series),
+            spectators,
+            rx,
+        }
+    }
+
+    /// Starts the battle once connected.
+    fn poll(&mut self, cx: &mut Context) -> Result<Option<Box<dyn Battle>>, Error> {
+        let connection = match self.rx.try_recv() {
+            Ok(connection) => connection?,
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(TryRecvError::Disconnected) => bail!("connecting to the opponent panicked"),
+        };
+
+        let series = self.series.take().expect("the battle already started");
+
+        let mut players = vec![NetPlayer::Remote(connection.peer)];
+        players.insert(connection.side, NetPlayer::Local(Handle::new(0)));
+        players.extend(self.spectators.drain(..));
+
+        let battle = battle::NetBattle::new(
+            cx,
+            series,
+            connection.bind,
+            &players,
+            connection.rtt,
+            connection.handshake,
+        )?;
+
+        Ok(Some(Box::new(battle)))
+    }
+}
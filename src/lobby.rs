@@ -0,0 +1,559 @@
+//! A tiny lobby for finding opponents.
+//!
+//! The lobby [`Server`] keeps a list of registered players and the rooms they
+//! have opened. Once a player joins a room, the server tells both players
+//! each other's address, and they connect to each other directly. The server
+//! is never part of the battle itself.
+//!
+//! Everything happens over UDP. Every request a [`Client`] sends gets exactly
+//! one response, and the client sends the request again if the response
+//! doesn't come. Because the server sees the address a client's packets come
+//! from, a client should register from the same address it will battle from.
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Error;
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// The default port of a lobby server.
+pub const DEFAULT_PORT: u16 = 19190;
+
+/// How long a player can go without sending anything before they are
+/// forgotten.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client waits for a response before asking again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many times a client asks before giving up.
+const MAX_ATTEMPTS: usize = 20;
+
+/// The size of the receive buffer for lobby packets.
+const PACKET_SIZE: usize = 8192;
+
+/// A room's id.
+pub type RoomId = u32;
+
+/// An open room.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RoomInfo {
+    /// The id of the room.
+    pub id: RoomId,
+    /// The name of the room.
+    pub name: String,
+    /// The name of the player who opened the room.
+    pub host: String,
+}
+
+/// A matched opponent.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Match {
+    /// The address of the opponent.
+    pub peer: SocketAddr,
+    /// The name of the opponent.
+    pub peer_name: String,
+    /// The side the local player plays on. `0` is left, `1` is right.
+    pub side: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Request {
+    Register {
+        name: String,
+    },
+    ListRooms,
+    CreateRoom {
+        name: String,
+    },
+    JoinRoom {
+        id: RoomId,
+    },
+    /// Asks if someone joined our room yet. Doubles as a heartbeat.
+    Poll,
+    Leave,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Response {
+    Registered { addr: SocketAddr },
+    Rooms(Vec<RoomInfo>),
+    RoomCreated { id: RoomId },
+    Waiting,
+    Matched(Match),
+    Left,
+    Error(String),
+}
+
+/// A lobby server.
+pub struct Server {
+    socket: UdpSocket,
+    clients: HashMap<SocketAddr, ClientEntry>,
+    rooms: HashMap<RoomId, Room>,
+    next_room: RoomId,
+}
+
+struct ClientEntry {
+    name: String,
+    last_seen: Instant,
+}
+
+struct Room {
+    name: String,
+    host: SocketAddr,
+    // set once someone joins
+    guest: Option<Guest>,
+}
+
+struct Guest {
+    addr: SocketAddr,
+    // kept with the room, the guest may leave before the host hears about
+    // them
+    name: String,
+}
+
+impl Server {
+    /// Binds a new `Server`.
+    pub fn bind(bind_addrs: impl ToSocketAddrs) -> Result<Server, Error> {
+        let socket = UdpSocket::bind(bind_addrs)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        Ok(Server {
+            socket,
+            clients: HashMap::new(),
+            rooms: HashMap::new(),
+            next_room: 1,
+        })
+    }
+
+    /// The address the server is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr().map_err(From::from)
+    }
+
+    /// Runs the server forever.
+    pub fn run(&mut self) -> Result<(), Error> {
+        info!("lobby listening on {}", self.local_addr()?);
+
+        loop {
+            self.poll()?;
+        }
+    }
+
+    /// Waits a little for requests and handles them.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        let mut buf = [0u8; PACKET_SIZE];
+
+        match self.socket.recv_from(&mut buf) {
+            Ok((len, addr)) => {
+                let response = match ron::de::from_bytes::<Request>(&buf[..len]) {
+                    Ok(request) => self.handle(addr, request),
+                    Err(e) => {
+                        warn!("bad request from {}: {}", addr, e);
+                        Response::Error(format!("bad request: {}", e))
+                    }
+                };
+
+                let response = ron::to_string(&response)?;
+                match self.socket.send_to(response.as_bytes(), addr) {
+                    Ok(_) => (),
+                    Err(e) => warn!("failed to respond to {}: {}", addr, e),
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        self.expire();
+
+        Ok(())
+    }
+
+    fn handle(&mut self, addr: SocketAddr, request: Request) -> Response {
+        if let Request::Register { name } = request {
+            info!("{} registered from {}", name, addr);
+
+            self.clients.insert(
+                addr,
+                ClientEntry {
+                    name,
+                    last_seen: Instant::now(),
+                },
+            );
+
+            return Response::Registered { addr };
+        }
+
+        match self.clients.get_mut(&addr) {
+            Some(client) => client.last_seen = Instant::now(),
+            // the client already left, but didn't hear back and asked again
+            None if matches!(request, Request::Leave) => return Response::Left,
+            None => return Response::Error("not registered".into()),
+        }
+
+        match request {
+            Request::Register { .. } => unreachable!(),
+            Request::ListRooms => {
+                let mut rooms = self
+                    .rooms
+                    .iter()
+                    .filter(|(_, room)| room.guest.is_none())
+                    .map(|(&id, room)| RoomInfo {
+                        id,
+                        name: room.name.clone(),
+                        host: self.clients[&room.host].name.clone(),
+                    })
+                    .collect::<Vec<_>>();
+                rooms.sort_by_key(|room| room.id);
+
+                Response::Rooms(rooms)
+            }
+            Request::CreateRoom { name } => {
+                // a player only gets one room at a time
+                if let Some((&id, _)) = self.rooms.iter().find(|(_, room)| room.host == addr) {
+                    return Response::RoomCreated { id };
+                }
+
+                let id = self.next_room;
+                self.next_room += 1;
+
+                info!(
+                    "{} opened room {} \"{}\"",
+                    self.clients[&addr].name, id, name
+                );
+
+                self.rooms.insert(
+                    id,
+                    Room {
+                        name,
+                        host: addr,
+                        guest: None,
+                    },
+                );
+
+                Response::RoomCreated { id }
+            }
+            Request::JoinRoom { id } => match self.rooms.get_mut(&id) {
+                Some(room) if room.host == addr => Response::Error("cannot join own room".into()),
+                Some(room)
+                    if room.guest.as_ref().map(|guest| guest.addr).unwrap_or(addr) == addr =>
+                {
+                    room.guest = Some(Guest {
+                        addr,
+                        name: self.clients[&addr].name.clone(),
+                    });
+
+                    info!("{} joined room {}", self.clients[&addr].name, id);
+
+                    Response::Matched(Match {
+                        peer: room.host,
+                        peer_name: self.clients[&room.host].name.clone(),
+                        side: 1,
+                    })
+                }
+                Some(_) => Response::Error(format!("room {} is full", id)),
+                None => Response::Error(format!("no room {}", id)),
+            },
+            Request::Poll => {
+                let room = self.rooms.values().find(|room| room.host == addr);
+
+                match room.and_then(|room| room.guest.as_ref()) {
+                    Some(guest) => Response::Matched(Match {
+                        peer: guest.addr,
+                        peer_name: guest.name.clone(),
+                        side: 0,
+                    }),
+                    None => Response::Waiting,
+                }
+            }
+            Request::Leave => {
+                self.remove(addr);
+                Response::Left
+            }
+        }
+    }
+
+    /// Forgets about clients that haven't been heard from in a while.
+    fn expire(&mut self) {
+        let expired = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.last_seen.elapsed() > CLIENT_TIMEOUT)
+            .map(|(&addr, _)| addr)
+            .collect::<Vec<_>>();
+
+        for addr in expired {
+            info!("{} timed out", addr);
+            self.remove(addr);
+        }
+    }
+
+    fn remove(&mut self, addr: SocketAddr) {
+        if let Some(client) = self.clients.remove(&addr) {
+            info!("{} left from {}", client.name, addr);
+        }
+
+        // close the rooms they opened. a guest leaves right after joining, so
+        // their room stays until the host has heard about the match and left
+        self.rooms.retain(|_, room| room.host != addr);
+    }
+}
+
+/// A lobby client.
+pub struct Client {
+    socket: UdpSocket,
+    server: SocketAddr,
+    addr: SocketAddr,
+}
+
+impl Client {
+    /// Registers with the lobby `server` under `name`.
+    ///
+    /// The address the client is bound to should be the address the battle
+    /// will be played from.
+    pub fn connect(
+        bind_addrs: impl ToSocketAddrs,
+        server: SocketAddr,
+        name: &str,
+    ) -> Result<Client, Error> {
+        let socket = UdpSocket::bind(bind_addrs)?;
+        socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+
+        let mut client = Client {
+            socket,
+            server,
+            addr: server,
+        };
+
+        match client.request(&Request::Register { name: name.into() })? {
+            Response::Registered { addr } => client.addr = addr,
+            response => bail!("unexpected response {:?}", response),
+        }
+
+        Ok(client)
+    }
+
+    /// The address the server sees the client as.
+    pub fn public_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The address the client is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr().map_err(From::from)
+    }
+
+    /// Lists the open rooms.
+    pub fn rooms(&self) -> Result<Vec<RoomInfo>, Error> {
+        match self.request(&Request::ListRooms)? {
+            Response::Rooms(rooms) => Ok(rooms),
+            response => bail!("unexpected response {:?}", response),
+        }
+    }
+
+    /// Opens a room for others to join.
+    pub fn create_room(&self, name: &str) -> Result<RoomId, Error> {
+        match self.request(&Request::CreateRoom { name: name.into() })? {
+            Response::RoomCreated { id } => Ok(id),
+            response => bail!("unexpected response {:?}", response),
+        }
+    }
+
+    /// Joins a room.
+    pub fn join(&self, id: RoomId) -> Result<Match, Error> {
+        match self.request(&Request::JoinRoom { id })? {
+            Response::Matched(m) => Ok(m),
+            response => bail!("unexpected response {:?}", response),
+        }
+    }
+
+    /// Waits for someone to join the client's room.
+    pub fn wait_for_match(&self, timeout: Duration) -> Result<Match, Error> {
+        let start = Instant::now();
+
+        while start.elapsed() < timeout {
+            match self.request(&Request::Poll)? {
+                Response::Matched(m) => return Ok(m),
+                Response::Waiting => std::thread::sleep(RESEND_INTERVAL),
+                response => bail!("unexpected response {:?}", response),
+            }
+        }
+
+        bail!("nobody joined within {:?}", timeout)
+    }
+
+    /// Leaves the lobby, closing the client's room.
+    ///
+    /// The socket is closed afterwards, so the same address can be bound
+    /// again for the battle.
+    pub fn leave(self) -> Result<(), Error> {
+        match self.request(&Request::Leave)? {
+            Response::Left => Ok(()),
+            response => bail!("unexpected response {:?}", response),
+        }
+    }
+
+    fn request(&self, request: &Request) -> Result<Response, Error> {
+        let request = ron::to_string(request)?;
+        let mut buf = [0u8; PACKET_SIZE];
+
+        for _ in 0..MAX_ATTEMPTS {
+            self.socket.send_to(request.as_bytes(), self.server)?;
+
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) if addr == self.server => {
+                    return match ron::de::from_bytes(&buf[..len])? {
+                        Response::Error(e) => Err(anyhow!("lobby error: {}", e)),
+                        response => Ok(response),
+                    };
+                }
+                Ok(_) => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    ()
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        bail!("lobby server {} isn't responding", self.server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn matchmaking() {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    server.poll().unwrap();
+                }
+            })
+        };
+
+        let alice = Client::connect("127.0.0.1:0", server_addr, "alice").unwrap();
+        let bob = Client::connect("127.0.0.1:0", server_addr, "bob").unwrap();
+        let carol = Client::connect("127.0.0.1:0", server_addr, "carol").unwrap();
+
+        assert_eq!(alice.public_addr(), alice.local_addr().unwrap());
+        assert!(bob.rooms().unwrap().is_empty());
+
+        let id = alice.create_room("casuals").unwrap();
+        assert_eq!(
+            bob.rooms().unwrap(),
+            vec![RoomInfo {
+                id,
+                name: "casuals".into(),
+                host: "alice".into(),
+            }]
+        );
+
+        let bob_match = bob.join(id).unwrap();
+        assert_eq!(bob_match.peer, alice.local_addr().unwrap());
+        assert_eq!(bob_match.peer_name, "alice");
+        assert_eq!(bob_match.side, 1);
+
+        let alice_match = alice.wait_for_match(Duration::from_secs(5)).unwrap();
+        assert_eq!(alice_match.peer, bob.local_addr().unwrap());
+        assert_eq!(alice_match.peer_name, "bob");
+        assert_eq!(alice_match.side, 0);
+
+        // the room is full now
+        assert!(carol.rooms().unwrap().is_empty());
+        assert!(carol.join(id).is_err());
+
+        alice.leave().unwrap();
+        bob.leave().unwrap();
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn guest_leaves_before_poll() {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    server.poll().unwrap();
+                }
+            })
+        };
+
+        let alice = Client::connect("127.0.0.1:0", server_addr, "alice").unwrap();
+        let bob = Client::connect("127.0.0.1:0", server_addr, "bob").unwrap();
+        let bob_addr = bob.local_addr().unwrap();
+
+        let id = alice.create_room("casuals").unwrap();
+        bob.join(id).unwrap();
+        bob.leave().unwrap();
+
+        // alice still hears about bob
+        let alice_match = alice.wait_for_match(Duration::from_secs(5)).unwrap();
+        assert_eq!(alice_match.peer, bob_addr);
+        assert_eq!(alice_match.peer_name, "bob");
+
+        alice.leave().unwrap();
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn leave_reply_lost() {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut request = |request: &Request| -> Response {
+            let request = ron::to_string(request).unwrap();
+            socket.send_to(request.as_bytes(), server_addr).unwrap();
+            server.poll().unwrap();
+
+            let mut buf = [0u8; PACKET_SIZE];
+            let (len, _) = socket.recv_from(&mut buf).unwrap();
+            ron::de::from_bytes(&buf[..len]).unwrap()
+        };
+
+        let register = Request::Register {
+            name: "alice".into(),
+        };
+        assert!(matches!(request(&register), Response::Registered { .. }));
+
+        // the first reply is lost, so the client asks again after it is gone
+        let _ = request(&Request::Leave);
+        assert!(matches!(request(&Request::Leave), Response::Left));
+
+        // anything else still needs a registration
+        assert!(matches!(request(&Request::Poll), Response::Error(_)));
+    }
+}
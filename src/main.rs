@@ -2,18 +2,30 @@ use anyhow::Error;
 
 use winit::{
     dpi::LogicalSize,
-    event::{DeviceEvent, ElementState, Event, WindowEvent},
+    event::{DeviceEvent, ElementState, Event, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
+use bftd::config::Subcommand;
 use bftd::Context;
 
+use std::path::PathBuf;
+use std::sync::Arc;
+
 pub fn main() -> Result<(), Error> {
     env_logger::init();
 
     let args = bftd::config::Args::from_args();
 
+    match &args.command {
+        Some(Subcommand::Pack { bundle, out, atlas }) => {
+            return bftd::assets::pack::write(bundle, out, *atlas)
+        }
+        Some(Subcommand::Validate { bundles }) => return validate(bundles),
+        None => (),
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_min_inner_size(LogicalSize::new(0, 100))
@@ -22,16 +34,19 @@ pub fn main() -> Result<(), Error> {
 
     let mut cx = Context {
         render: bftd::render::Context::new(&window)?,
-        script: bftd::battle::script::Engine::new(),
+        script: Arc::new(bftd::battle::script::Engine::new()),
         input: bftd::input::Sampler::new(Default::default()),
         frame_limiter: bftd::timer::FrameLimiter::new(),
         task_pool: bevy_tasks::TaskPool::new(),
         args,
     };
 
+    cx.render.set_debug(cx.args.debug_boxes);
+
     let mut game = bftd::Game::new(&mut cx)?;
 
     let mut focused = true;
+    let mut stats_limiter = bftd::timer::FrameLimiter::new();
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -59,13 +74,17 @@ pub fn main() -> Result<(), Error> {
                         ElementState::Pressed => cx.input.process_key_down(key.scancode),
                         ElementState::Released => cx.input.process_key_up(key.scancode),
                     }
+
+                    // toggle the debug overlay
+                    if key.state == ElementState::Pressed
+                        && key.virtual_keycode == Some(VirtualKeyCode::F3)
+                    {
+                        cx.render.set_debug(!cx.render.debug());
+                    }
                 }
             },
             Event::RedrawRequested(_) => {
                 cx.render.begin(|mut cx| {
-                    // set camera transform
-                    // TODO: move this somewhere that makes sense
-                    //cx.set_transform(Affine2::from_scale(Vec2::new(1. / 500., 1. / 500.)));
                     game.draw(&mut cx);
                 });
             }
@@ -79,10 +98,49 @@ pub fn main() -> Result<(), Error> {
                     cx.input.poll();
                 }
 
-                game.update(&mut cx);
+                if let Err(e) = game.update(&mut cx) {
+                    log::error!("battle failed: {:?}", e);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+
+                if let Some(outcome) = game.outcome() {
+                    log::info!("game over: {:?}", outcome);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+
+                // show the status, but not often enough to be unreadable
+                if stats_limiter.should_update(2) {
+                    let mut title = env!("CARGO_PKG_NAME").to_owned();
+
+                    if let Some(status) = game.status() {
+                        title += &format!(" | {}", status);
+                    }
+
+                    window.set_title(&title);
+                }
+
                 window.request_redraw();
             }
             _ => {}
         }
     });
 }
+
+/// Validates bundles, printing every problem found.
+fn validate(bundles: &[PathBuf]) -> Result<(), Error> {
+    let problems =
+        bftd::assets::validate::validate_bundles(bundles, &bftd::battle::script::Engine::new());
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+
+    if problems.is_empty() {
+        println!("no problems found");
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("found {} problem(s)", problems.len()))
+    }
+}
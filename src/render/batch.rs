@@ -0,0 +1,237 @@
+//! Sprite batching.
+//!
+//! Drawing a sprite doesn't touch the GPU. The [`DrawCommand`]s of a frame are
+//! drawn all at once when the frame ends: sorted by layer, with the data of
+//! every sprite uploaded in a single buffer, and one instanced draw for each
+//! run of adjacent sprites that share a texture and palette, all in one render
+//! pass.
+
+use super::{Context, DrawCommand, Texture};
+
+use std::ops::Range;
+
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+
+/// The data of a single sprite, as read by the sprite shader.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+pub struct Instance {
+    /// The columns of the transform from the unit quad to clip space.
+    pub transform: [[f32; 2]; 3],
+    /// The left, bottom, width and height of the source rectangle.
+    pub src: [f32; 4],
+    /// The colour to multiply the texture with.
+    pub tint: [f32; 4],
+    /// The colour to add to the texture after the tint, by its alpha.
+    pub flash: [f32; 4],
+}
+
+impl Instance {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 6] = wgpu::vertex_attr_array![
+        0 => Float32x2,
+        1 => Float32x2,
+        2 => Float32x2,
+        3 => Float32x4,
+        4 => Float32x4,
+        5 => Float32x4,
+    ];
+
+    /// Creates the instance of a draw command.
+    pub fn new(command: &DrawCommand) -> Instance {
+        let DrawCommand {
+            transform,
+            src,
+            tint,
+            flash,
+            ..
+        } = command;
+
+        Instance {
+            transform: [
+                transform.matrix2.x_axis.into(),
+                transform.matrix2.y_axis.into(),
+                transform.translation.into(),
+            ],
+            src: [src.left(), src.bottom(), src.width(), src.height()],
+            tint: (*tint).into(),
+            flash: (*flash).into(),
+        }
+    }
+
+    /// The layout of the instance buffer.
+    pub fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Instance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Draws the commands of a frame to `view`, clearing it first.
+///
+/// Commands on a lower layer are drawn under commands on a higher layer.
+/// Within a layer, commands are drawn in the order they were recorded in.
+/// Commands with headless textures or palettes are skipped.
+pub fn draw(
+    cx: &Context,
+    mut commands: Vec<DrawCommand>,
+    view: &wgpu::TextureView,
+    encoder: &mut wgpu::CommandEncoder,
+) {
+    commands.retain(|c| {
+        c.texture.as_ref().is_none_or(|t| t.gpu.is_some())
+            && c.palette.as_ref().is_none_or(|p| p.gpu.is_some())
+    });
+
+    let batches = batches(&cx.white, &mut commands);
+
+    let instances = commands.iter().map(Instance::new).collect::<Vec<_>>();
+    let buffer = (!instances.is_empty()).then(|| {
+        cx.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("sprite instances"),
+                contents: bytemuck::cast_slice(&instances),
+                usage: wgpu::BufferUsages::VERTEX,
+            })
+    });
+
+    let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: None,
+        color_attachments: &[wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: true,
+            },
+        }],
+        depth_stencil_attachment: None,
+    });
+
+    // nothing to draw, but the frame still has to be cleared
+    let buffer = match &buffer {
+        Some(buffer) => buffer,
+        None => return,
+    };
+
+    rpass.set_vertex_buffer(0, buffer.slice(..));
+
+    for batch in batches {
+        let first = &commands[batch.start];
+
+        match first.palette.as_ref().and_then(|p| p.gpu.as_ref()) {
+            Some(remap) => {
+                rpass.set_pipeline(cx.sprite.remap_pipeline());
+                rpass.set_bind_group(1, &remap.bind_group, &[]);
+            }
+            None => rpass.set_pipeline(cx.sprite.pipeline()),
+        }
+
+        if let Some(gpu) = &texture(&cx.white, first).gpu {
+            rpass.set_bind_group(0, &gpu.bind_group, &[]);
+            rpass.draw(0..6, batch.start as u32..batch.end as u32);
+        }
+    }
+}
+
+/// Sorts `commands` into drawing order and splits them into batches that can
+/// each be drawn with a single instanced draw.
+///
+/// The sort is by layer alone, and stable, so commands on the same layer stay
+/// in the order they were recorded in. A batch is a run of adjacent commands
+/// that share a texture and palette.
+fn batches(white: &Texture, commands: &mut [DrawCommand]) -> Vec<Range<usize>> {
+    commands.sort_by_key(|c| c.layer);
+
+    let mut batches = Vec::new();
+    let mut start = 0;
+    while start < commands.len() {
+        let first = &commands[start];
+        let key = (texture(white, first).id, palette(first));
+        let end = commands[start..]
+            .iter()
+            .position(|c| (texture(white, c).id, palette(c)) != key)
+            .map(|len| start + len)
+            .unwrap_or(commands.len());
+
+        batches.push(start..end);
+        start = end;
+    }
+
+    batches
+}
+
+/// The id of the palette a command is drawn through, if any.
+fn palette(command: &DrawCommand) -> Option<u64> {
+    command.palette.as_ref().map(Texture::id)
+}
+
+/// The texture a command is drawn with. Solid quads are drawn with a white
+/// texture.
+fn texture<'a>(white: &'a Texture, command: &'a DrawCommand) -> &'a Texture {
+    command.texture.as_ref().unwrap_or(white)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{Color, DrawParams, Renderer};
+
+    use bftd_lib::Rect;
+    use glam::f32::Affine2;
+
+    #[test]
+    fn keeps_recorded_order() {
+        let white = Texture::headless(1, 1);
+        let p1 = Texture::headless(64, 64);
+        let p2 = Texture::headless(64, 64);
+        let stage = Texture::headless(256, 128);
+        let src = Rect::new(0., 0., 1., 1.);
+
+        // p2 is drawn first so p1 lands on top of it, even though p2's
+        // texture came later; the stage is recorded last, but on a lower layer
+        let mut renderer = Renderer::new(1.);
+        renderer.draw_texture(&p2, src.clone(), Affine2::IDENTITY, Color::WHITE);
+        renderer.draw_texture(&p1, src.clone(), Affine2::IDENTITY, Color::WHITE);
+        renderer.draw_texture(&p1, src.clone(), Affine2::IDENTITY, Color::WHITE);
+        renderer.set_layer(-1);
+        renderer.draw_texture(&stage, src, Affine2::IDENTITY, Color::WHITE);
+
+        let mut commands = renderer.into_commands();
+        let batches = batches(&white, &mut commands);
+
+        let ids = commands
+            .iter()
+            .map(|c| texture(&white, c).id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [stage.id(), p2.id(), p1.id(), p1.id()]);
+        assert_eq!(batches, [0..1, 1..2, 2..4]);
+    }
+
+    #[test]
+    fn splits_on_palette() {
+        let white = Texture::headless(1, 1);
+        let sheet = Texture::headless(64, 64);
+        let remap = Texture::headless(256, 16);
+        let src = Rect::new(0., 0., 1., 1.);
+
+        // a mirror match: the same sheet, one side through a palette
+        let mut renderer = Renderer::new(1.);
+        renderer.draw_texture(&sheet, src.clone(), Affine2::IDENTITY, Color::WHITE);
+        renderer.draw_texture_with(
+            &sheet,
+            src.clone(),
+            Affine2::IDENTITY,
+            DrawParams {
+                palette: Some(remap),
+                ..Default::default()
+            },
+        );
+        renderer.fill_rect(&src, Affine2::IDENTITY, Color::BLACK);
+
+        let mut commands = renderer.into_commands();
+        assert_eq!(batches(&white, &mut commands), [0..1, 1..2, 2..3]);
+    }
+}
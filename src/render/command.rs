@@ -0,0 +1,58 @@
+//! Recorded draw commands.
+//!
+//! A [`Renderer`](super::Renderer) doesn't draw anything itself. It records a
+//! [`DrawCommand`] for everything drawn to it, which a backend draws later.
+//! The only backend is the [`wgpu`] one in [`Context`](super::Context), but
+//! the commands can just as well be looked at in a test, with no GPU around.
+
+use super::{Color, Texture};
+
+use bftd_lib::Rect;
+use glam::f32::Affine2;
+
+/// A textured or solid quad to draw.
+#[derive(Clone, Debug)]
+pub struct DrawCommand {
+    /// The texture to draw from.
+    ///
+    /// If there is none, the quad is filled with the tint.
+    pub texture: Option<Texture>,
+    /// The source rectangle, where the whole texture is `0` to `1`.
+    pub src: Rect,
+    /// The transform from a unit quad, centered on the origin, to clip space.
+    ///
+    /// This includes the world and clip transforms of the renderer.
+    pub transform: Affine2,
+    /// The layer to draw on.
+    pub layer: i32,
+    /// The colour to multiply the texture with.
+    pub tint: Color,
+    /// The colour added to the texture after the tint, by its alpha.
+    pub flash: Color,
+    /// The colour-remap texture to look the colours of the texture up in,
+    /// if any. See [`bftd_lib::character::Palette`].
+    pub palette: Option<Texture>,
+}
+
+/// How a texture is coloured when drawn with
+/// [`Renderer::draw_texture_with`](super::Renderer::draw_texture_with).
+#[derive(Clone, Debug)]
+pub struct DrawParams {
+    /// The colour to multiply the texture with.
+    pub tint: Color,
+    /// The colour added to the texture after the tint, by its alpha.
+    pub flash: Color,
+    /// The colour-remap texture to look the colours of the texture up in,
+    /// if any.
+    pub palette: Option<Texture>,
+}
+
+impl Default for DrawParams {
+    fn default() -> DrawParams {
+        DrawParams {
+            tint: Color::WHITE,
+            flash: Color::TRANSPARENT,
+            palette: None,
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Bitmap fonts.
+//!
+//! Fonts are read from [BDF] files, a plain text format most bitmap font
+//! editors can export to. Glyphs are looked up by the `ENCODING` of each
+//! character, which is taken to be a Unicode code point.
+//!
+//! [BDF]: https://en.wikipedia.org/wiki/Glyph_Bitmap_Distribution_Format
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Error;
+
+/// A bitmap font.
+#[derive(Debug)]
+pub struct Font {
+    id: u64,
+    ascent: i32,
+    descent: i32,
+    glyphs: HashMap<char, Glyph>,
+}
+
+/// A single glyph of a [`Font`], in pixels.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Glyph {
+    /// The width of the bitmap.
+    pub width: u32,
+    /// The height of the bitmap.
+    pub height: u32,
+    /// The offset of the bottom-left corner of the bitmap from the pen, which
+    /// sits on the baseline.
+    pub offset: (i32, i32),
+    /// How far the pen moves after the glyph.
+    pub advance: i32,
+    // one byte per pixel, top row first, 255 where the glyph is set
+    coverage: Vec<u8>,
+}
+
+impl Font {
+    /// Parses a font from a BDF file.
+    pub fn parse<R>(read: R) -> Result<Font, Error>
+    where
+        R: Read,
+    {
+        let mut lines = BufReader::new(read).lines();
+
+        let mut bounds = None;
+        let (mut ascent, mut descent) = (None, None);
+        let mut glyphs = HashMap::new();
+
+        // the glyph being read, and its code point
+        let mut glyph: Option<(Option<char>, Glyph)> = None;
+
+        while let Some(line) = lines.next() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let numbers = words
+                .map(|w| w.parse::<i32>())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_or_default();
+
+            if keyword == "ENDCHAR" {
+                // glyphs without a code point can't be typed, so they aren't
+                // kept
+                if let Some((Some(code), glyph)) = glyph.take() {
+                    glyphs.insert(code, glyph);
+                }
+
+                continue;
+            }
+
+            match (keyword, glyph.as_mut()) {
+                ("FONTBOUNDINGBOX", None) => bounds = Some(numbers),
+                ("FONT_ASCENT", None) => ascent = numbers.first().copied(),
+                ("FONT_DESCENT", None) => descent = numbers.first().copied(),
+                ("STARTCHAR", None) => glyph = Some((None, Glyph::default())),
+                ("ENCODING", Some((code, _))) => {
+                    *code = numbers
+                        .first()
+                        .and_then(|&n| u32::try_from(n).ok())
+                        .and_then(char::from_u32);
+                }
+                ("DWIDTH", Some((_, glyph))) => {
+                    glyph.advance = *numbers
+                        .first()
+                        .ok_or_else(|| anyhow!("invalid DWIDTH: {}", line))?;
+                }
+                ("BBX", Some((_, glyph))) => match numbers[..] {
+                    [width, height, x, y] => {
+                        glyph.width = u32::try_from(width)?;
+                        glyph.height = u32::try_from(height)?;
+                        glyph.offset = (x, y);
+                    }
+                    _ => bail!("invalid BBX: {}", line),
+                },
+                ("BITMAP", Some((_, glyph))) => {
+                    for _ in 0..glyph.height {
+                        let row = lines
+                            .next()
+                            .ok_or_else(|| anyhow!("glyph bitmap ends early"))??;
+                        read_row(row.trim(), glyph)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // fall back to the bounding box of the whole font
+        let (bounds_ascent, bounds_descent) = match bounds.as_deref() {
+            Some(&[_, height, _, y]) => (height + y, -y),
+            _ => (0, 0),
+        };
+
+        Ok(Font {
+            id: NEXT_FONT_ID.fetch_add(1, Ordering::Relaxed),
+            ascent: ascent.unwrap_or(bounds_ascent),
+            descent: descent.unwrap_or(bounds_descent),
+            glyphs,
+        })
+    }
+
+    /// A number unique to this font.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How far the font reaches above the baseline, in pixels.
+    pub fn ascent(&self) -> i32 {
+        self.ascent
+    }
+
+    /// How far the font reaches below the baseline, in pixels.
+    pub fn descent(&self) -> i32 {
+        self.descent
+    }
+
+    /// The distance between the baselines of two lines, in pixels.
+    pub fn line_height(&self) -> i32 {
+        self.ascent + self.descent
+    }
+
+    /// Finds the glyph of a character.
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs.get(&c)
+    }
+}
+
+impl Glyph {
+    /// The coverage of each pixel of the glyph, top row first, from `0` to
+    /// `255`.
+    pub fn coverage(&self) -> &[u8] {
+        &self.coverage
+    }
+}
+
+/// Reads a row of a BDF bitmap. Rows are hex, most significant bit first,
+/// padded to whole bytes.
+fn read_row(row: &str, glyph: &mut Glyph) -> Result<(), Error> {
+    let bytes = (0..row.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(row.get(i..i + 2).unwrap_or("0"), 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("invalid bitmap row {}: {}", row, e))?;
+
+    for x in 0..glyph.width as usize {
+        let set = matches!(bytes.get(x / 8), Some(b) if b & (0x80 >> (x % 8)) != 0);
+        glyph.coverage.push(if set { 255 } else { 0 });
+    }
+
+    Ok(())
+}
+
+/// Gives each font an id to cache its glyphs by.
+static NEXT_FONT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let bdf = "\
+STARTFONT 2.1
+FONTBOUNDINGBOX 3 4 0 -1
+STARTPROPERTIES 1
+FONT_ASCENT 3
+ENDPROPERTIES
+CHARS 2
+STARTCHAR space
+ENCODING 32
+DWIDTH 4 0
+BBX 0 0 0 0
+BITMAP
+ENDCHAR
+STARTCHAR y
+ENCODING 121
+DWIDTH 4 0
+BBX 3 4 0 -1
+BITMAP
+A0
+A0
+60
+C0
+ENDCHAR
+ENDFONT
+";
+        let font = Font::parse(bdf.as_bytes()).unwrap();
+
+        // the descent comes from the bounding box
+        assert_eq!((font.ascent(), font.descent()), (3, 1));
+        assert_eq!(font.glyph(' ').unwrap().width, 0);
+        assert_eq!(font.glyph('x'), None);
+
+        let y = font.glyph('y').unwrap();
+        assert_eq!((y.width, y.height, y.offset, y.advance), (3, 4, (0, -1), 4));
+        assert_eq!(
+            y.coverage(),
+            [255, 0, 255, 255, 0, 255, 0, 255, 255, 255, 255, 0]
+        );
+    }
+}
@@ -1,11 +1,19 @@
 //! 2D rendering using [`wgpu`].
 //!
-//! This also exposes [`wgpu`] types if you need to implement your own shaders,
-//! for whatever reason.
+//! Drawing goes through a [`Renderer`], which records [`DrawCommand`]s that
+//! the [`Context`] then draws with [`wgpu`]. This also exposes [`wgpu`] types
+//! if you need to implement your own shaders, for whatever reason.
 
+mod batch;
+mod command;
+pub mod font;
 mod sprite;
+mod text;
 
+pub use command::{DrawCommand, DrawParams};
+pub use font::Font;
 pub use sprite::Sprite;
+pub use text::{GlyphCache, Text};
 
 use pollster::FutureExt as _;
 
@@ -13,8 +21,14 @@ use glam::f32::{Affine2, Vec2};
 use wgpu::util::DeviceExt;
 use winit::window::Window;
 
+use bftd_lib::Rect;
+
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter};
 use std::io::{BufReader, Read, Seek};
-use std::ops::Deref;
+use std::num::NonZeroU32;
+use std::ops::Mul;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use anyhow::Error;
@@ -27,6 +41,12 @@ pub struct Context {
     surface_config: wgpu::SurfaceConfiguration,
 
     sprite: sprite::Shader,
+    // stands in for the texture of solid quads
+    white: Texture,
+    // lent to the renderer of each frame
+    glyphs: RefCell<GlyphCache>,
+
+    debug: bool,
 }
 
 impl Context {
@@ -84,15 +104,26 @@ impl Context {
 
         surface.configure(&device, &surface_config);
 
-        Ok(Context {
+        let mut cx = Context {
             // build the default render layouts
             sprite: sprite::Shader::new(&device, &surface_config),
+            white: Texture::headless(1, 1),
+            glyphs: RefCell::default(),
+            debug: false,
             // finalize
             device: Arc::new(device),
             queue,
             surface,
             surface_config,
-        })
+        };
+
+        cx.white = cx.create_texture(&image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])));
+
+        let size = GlyphCache::SIZE;
+        let glyphs = cx.create_texture(&image::RgbaImage::new(size, size));
+        cx.glyphs = RefCell::new(GlyphCache::new(glyphs));
+
+        Ok(cx)
     }
 
     /// Resizes the swapchain texture.
@@ -107,13 +138,32 @@ impl Context {
         self.surface_config.height as f32 / self.surface_config.width as f32
     }
 
+    /// Whether debug overlays are drawn.
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    /// Sets whether debug overlays are drawn. See [`Renderer::debug`].
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
     /// Begins a render frame, calls the closure and finalizes the frame.
+    ///
+    /// Everything drawn in the closure is recorded, and only batched and sent
+    /// to the GPU once it returns.
     pub fn begin<F>(&self, f: F)
     where
         F: FnOnce(&mut Renderer),
     {
-        // find clip transform
-        let clip = Affine2::from_scale(Vec2::new(self.aspect_ratio() * 2., 2.));
+        let mut renderer = Renderer::new(self.aspect_ratio());
+        renderer.set_debug(self.debug);
+        renderer.glyphs = self.glyphs.take();
+
+        f(&mut renderer);
+
+        self.upload_glyphs(&mut renderer.glyphs);
+        self.glyphs.replace(std::mem::take(&mut renderer.glyphs));
 
         // create swapchain view
         let frame = self
@@ -127,46 +177,57 @@ impl Context {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            // clear screen
-            let _rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
-        }
-
-        f(&mut Renderer {
-            cx: self,
-
-            world: Affine2::IDENTITY,
-            clip,
 
-            view,
-            encoder: &mut encoder,
-        });
+        batch::draw(self, renderer.into_commands(), &view, &mut encoder);
 
         self.queue.submit(Some(encoder.finish()));
         frame.present();
     }
 
+    /// Uploads the glyphs rasterized since the last upload.
+    fn upload_glyphs(&self, glyphs: &mut GlyphCache) {
+        let texture = glyphs.texture().clone();
+        let (pixels, raw) = match (glyphs.take_dirty(), texture.raw()) {
+            (Some(pixels), Some(raw)) => (pixels, raw),
+            _ => return,
+        };
+
+        let size = GlyphCache::SIZE;
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: raw,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(size * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
     /// Loads a 2D texture as an image from a stream.
     pub fn load_texture<R>(&self, read: R) -> Result<Texture, Error>
     where
         R: Read + Seek,
     {
-        let image = image::io::Reader::new(BufReader::new(read))
-            .with_guessed_format()?
-            .decode()?
-            .into_rgba8();
+        Ok(self.create_texture(&decode_image(read)?))
+    }
 
+    /// Uploads a decoded image to a 2D texture.
+    ///
+    /// Decoding is the slow part of loading a texture. It can be done on any
+    /// thread with [`decode_image`], leaving only the upload for the thread
+    /// that owns the context.
+    pub fn create_texture(&self, image: &image::RgbaImage) -> Texture {
         let texture = self.device.create_texture_with_data(
             &self.queue,
             &wgpu::TextureDescriptor {
@@ -185,26 +246,69 @@ impl Context {
             image.as_raw(),
         );
 
-        Ok(Texture {
-            texture: Arc::new(texture),
+        Texture {
+            id: NEXT_TEXTURE_ID.fetch_add(1, Ordering::Relaxed),
+            gpu: Some(Arc::new(Gpu {
+                bind_group: self.sprite.bind_group(&self.device, &texture),
+                texture,
+            })),
             dims: (image.width(), image.height()),
-        })
+        }
     }
 }
 
+/// Decodes an image from a stream, guessing its format.
+pub fn decode_image<R>(read: R) -> Result<image::RgbaImage, Error>
+where
+    R: Read + Seek,
+{
+    let image = image::io::Reader::new(BufReader::new(read))
+        .with_guessed_format()?
+        .decode()?
+        .into_rgba8();
+
+    Ok(image)
+}
+
 /// A single frame to draw to.
-pub struct Renderer<'a> {
-    cx: &'a Context,
+///
+/// This only records [`DrawCommand`]s, so a `Renderer` can be made without a
+/// GPU with [`Renderer::new`] and looked at with [`Renderer::commands`].
+pub struct Renderer {
+    aspect_ratio: f32,
 
     // transformation matrices
     world: Affine2,
     clip: Affine2,
+    layer: i32,
+    debug: bool,
 
-    view: wgpu::TextureView,
-    encoder: &'a mut wgpu::CommandEncoder,
+    commands: Vec<DrawCommand>,
+    glyphs: GlyphCache,
 }
 
-impl<'a> Renderer<'a> {
+impl Renderer {
+    /// Creates a `Renderer` for a frame with the given aspect ratio, height
+    /// over width.
+    pub fn new(aspect_ratio: f32) -> Renderer {
+        Renderer {
+            aspect_ratio,
+
+            world: Affine2::IDENTITY,
+            clip: Affine2::from_scale(Vec2::new(aspect_ratio * 2., 2.)),
+            layer: 0,
+            debug: false,
+
+            commands: Vec::new(),
+            glyphs: GlyphCache::default(),
+        }
+    }
+
+    /// The aspect ratio of the frame, height over width.
+    pub fn aspect_ratio(&self) -> f32 {
+        self.aspect_ratio
+    }
+
     /// The world transform.
     pub fn transform(&self) -> Affine2 {
         self.world
@@ -217,13 +321,150 @@ impl<'a> Renderer<'a> {
     pub fn set_transform(&mut self, world: Affine2) {
         self.world = world;
     }
+
+    /// The layer sprites are drawn on.
+    pub fn layer(&self) -> i32 {
+        self.layer
+    }
+
+    /// Sets the layer sprites are drawn on.
+    ///
+    /// Sprites on higher layers are drawn over sprites on lower layers. The
+    /// default layer is `0`.
+    pub fn set_layer(&mut self, layer: i32) {
+        self.layer = layer;
+    }
+
+    /// Whether debug overlays should be drawn.
+    ///
+    /// Drawables check this themselves; nothing is hidden by the renderer.
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    /// Sets whether debug overlays should be drawn.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Draws the `src` of a texture on a unit quad centered on the origin,
+    /// transformed by `transform` and then the world and clip transforms.
+    pub fn draw_texture(&mut self, texture: &Texture, src: Rect, transform: Affine2, tint: Color) {
+        let params = DrawParams {
+            tint,
+            ..Default::default()
+        };
+
+        self.draw_texture_with(texture, src, transform, params);
+    }
+
+    /// Draws the `src` of a texture like [`Renderer::draw_texture`], coloured
+    /// by `params`.
+    pub fn draw_texture_with(
+        &mut self,
+        texture: &Texture,
+        src: Rect,
+        transform: Affine2,
+        params: DrawParams,
+    ) {
+        self.commands.push(DrawCommand {
+            texture: Some(texture.clone()),
+            src,
+            transform: self.clip * self.world * transform,
+            layer: self.layer,
+            tint: params.tint,
+            flash: params.flash,
+            palette: params.palette,
+        });
+    }
+
+    /// Fills a rectangle with a colour.
+    ///
+    /// The rectangle is transformed by `transform` and then the world and clip
+    /// transforms.
+    pub fn fill_rect(&mut self, rect: &Rect, transform: Affine2, color: Color) {
+        let quad = Affine2::from_translation(rect.center())
+            * Affine2::from_scale(Vec2::new(rect.width(), rect.height()));
+
+        self.commands.push(DrawCommand {
+            texture: None,
+            src: Rect::new(0., 0., 1., 1.),
+            transform: self.clip * self.world * transform * quad,
+            layer: self.layer,
+            tint: color,
+            flash: Color::TRANSPARENT,
+            palette: None,
+        });
+    }
+
+    /// The commands recorded so far, in the order they were drawn.
+    pub fn commands(&self) -> &[DrawCommand] {
+        &self.commands
+    }
+
+    /// Takes the recorded commands.
+    pub fn into_commands(self) -> Vec<DrawCommand> {
+        self.commands
+    }
+}
+
+/// An RGBA colour, with each channel from `0` to `1`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Color {
+    /// Opaque white, which leaves textures as they are.
+    pub const WHITE: Color = Color::rgba(1., 1., 1., 1.);
+    /// Opaque black.
+    pub const BLACK: Color = Color::rgba(0., 0., 0., 1.);
+    /// Fully transparent, which adds nothing as a flash.
+    pub const TRANSPARENT: Color = Color::rgba(0., 0., 0., 0.);
+
+    /// Creates a colour from its channels.
+    pub const fn rgba(r: f32, g: f32, b: f32, a: f32) -> Color {
+        Color { r, g, b, a }
+    }
+
+    /// The same colour with a different alpha.
+    pub const fn with_alpha(self, a: f32) -> Color {
+        Color { a, ..self }
+    }
+}
+
+impl Default for Color {
+    fn default() -> Color {
+        Color::WHITE
+    }
+}
+
+/// Multiplies colours channel by channel.
+impl Mul for Color {
+    type Output = Color;
+
+    fn mul(self, other: Color) -> Color {
+        Color::rgba(
+            self.r * other.r,
+            self.g * other.g,
+            self.b * other.b,
+            self.a * other.a,
+        )
+    }
 }
 
-impl<'a> Deref for Renderer<'a> {
-    type Target = Context;
+impl From<Color> for [f32; 4] {
+    fn from(color: Color) -> [f32; 4] {
+        [color.r, color.g, color.b, color.a]
+    }
+}
 
-    fn deref(&self) -> &Context {
-        self.cx
+impl From<[f32; 4]> for Color {
+    fn from([r, g, b, a]: [f32; 4]) -> Color {
+        Color { r, g, b, a }
     }
 }
 
@@ -232,11 +473,36 @@ impl<'a> Deref for Renderer<'a> {
 /// Cheaply cloneable.
 #[derive(Clone)]
 pub struct Texture {
-    texture: Arc<wgpu::Texture>,
+    id: u64,
+    // missing for headless textures
+    gpu: Option<Arc<Gpu>>,
     dims: (u32, u32),
 }
 
+struct Gpu {
+    texture: wgpu::Texture,
+    // created once, so drawing the texture doesn't have to
+    bind_group: wgpu::BindGroup,
+}
+
 impl Texture {
+    /// Creates a texture that only has a size, with nothing on the GPU.
+    ///
+    /// Drawing it records commands like any other texture, but the backend
+    /// skips it. This is for tests and tools that have no GPU.
+    pub fn headless(width: u32, height: u32) -> Texture {
+        Texture {
+            id: NEXT_TEXTURE_ID.fetch_add(1, Ordering::Relaxed),
+            gpu: None,
+            dims: (width, height),
+        }
+    }
+
+    /// A number unique to this texture and its clones.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     /// The width of the texture.
     pub fn width(&self) -> u32 {
         self.dims.0
@@ -246,8 +512,25 @@ impl Texture {
     pub fn height(&self) -> u32 {
         self.dims.1
     }
+
+    /// The underlying [`wgpu::Texture`], if the texture isn't headless.
+    pub fn raw(&self) -> Option<&wgpu::Texture> {
+        self.gpu.as_ref().map(|gpu| &gpu.texture)
+    }
+}
+
+impl Debug for Texture {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Texture")
+            .field("id", &self.id)
+            .field("dims", &self.dims)
+            .finish_non_exhaustive()
+    }
 }
 
+/// Gives each texture an id to batch sprites by.
+static NEXT_TEXTURE_ID: AtomicU64 = AtomicU64::new(0);
+
 /// A trait for drawable items.
 pub trait Drawable {
     /// Draws the item to the screen.
@@ -1,17 +1,22 @@
 //! Sprite renderer.
 
-use super::{Drawable, Renderer, Texture};
+use super::batch::Instance;
+use super::{Color, DrawParams, Drawable, Renderer, Texture};
 
 use std::fmt::{self, Debug, Formatter};
 
 use bftd_lib::Rect;
-use glam::f32::{Affine2, Mat3, Mat4, Vec2};
-use wgpu::util::DeviceExt;
+use glam::f32::{Affine2, Vec2};
 
 /// Sprite shader.
+///
+/// Sprites drawn through a palette use a pipeline of their own, which binds
+/// the colour-remap texture as a second group. Both groups have the same
+/// layout, so any texture can be bound as either.
 pub struct Shader {
     bind_group_layout: wgpu::BindGroupLayout,
     pipeline: wgpu::RenderPipeline,
+    remap_pipeline: wgpu::RenderPipeline,
 
     sampler: wgpu::Sampler,
 }
@@ -40,26 +45,6 @@ impl Shader {
                     },
                     count: None,
                 },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(64),
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(64),
-                    },
-                    count: None,
-                },
             ],
         });
         let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -67,30 +52,40 @@ impl Shader {
             bind_group_layouts: &[&bind_group_layout],
             push_constant_ranges: &[],
         });
-
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[wgpu::ColorTargetState {
-                    format: surface_config.format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState::default(),
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState::default(),
-            multiview: None,
+        let remap_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("sprite remap shader layout"),
+            bind_group_layouts: &[&bind_group_layout, &bind_group_layout],
+            push_constant_ranges: &[],
         });
 
+        let create_pipeline = |layout: &wgpu::PipelineLayout, entry_point: &str| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: None,
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[Instance::layout()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[wgpu::ColorTargetState {
+                        format: surface_config.format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+
+        let pipeline = create_pipeline(&layout, "fs_main");
+        let remap_pipeline = create_pipeline(&remap_layout, "fs_remap");
+
         // create render pipeline defaults
         // sampler
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
@@ -107,10 +102,46 @@ impl Shader {
         Shader {
             bind_group_layout,
             pipeline,
+            remap_pipeline,
 
             sampler,
         }
     }
+
+    /// The render pipeline.
+    pub fn pipeline(&self) -> &wgpu::RenderPipeline {
+        &self.pipeline
+    }
+
+    /// The render pipeline for sprites drawn through a palette.
+    ///
+    /// The colour-remap texture is bound to group `1`.
+    pub fn remap_pipeline(&self) -> &wgpu::RenderPipeline {
+        &self.remap_pipeline
+    }
+
+    /// Creates the bind group to draw a texture with.
+    pub fn bind_group(&self, device: &wgpu::Device, texture: &wgpu::Texture) -> wgpu::BindGroup {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("texture"),
+            ..Default::default()
+        });
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+            ],
+            label: None,
+        })
+    }
 }
 
 /// A sprite to be rendered to the screen.
@@ -119,6 +150,10 @@ pub struct Sprite {
     texture: Texture,
     src: Rect,
     transform: Affine2,
+    tint: Color,
+    flash: Color,
+    opacity: f32,
+    palette: Option<Texture>,
 }
 
 impl Sprite {
@@ -131,6 +166,10 @@ impl Sprite {
                 p2: Vec2::ONE,
             },
             transform: Default::default(),
+            tint: Color::WHITE,
+            flash: Color::TRANSPARENT,
+            opacity: 1.,
+            palette: None,
         };
 
         sprite
@@ -155,6 +194,51 @@ impl Sprite {
     pub fn set_transform(&mut self, transform: Affine2) {
         self.transform = transform;
     }
+
+    /// The colour the sprite is multiplied with.
+    pub fn tint(&self) -> Color {
+        self.tint
+    }
+
+    /// Sets the colour the sprite is multiplied with.
+    pub fn set_tint(&mut self, tint: Color) {
+        self.tint = tint;
+    }
+
+    /// The colour added to the sprite after the tint, by its alpha.
+    pub fn flash(&self) -> Color {
+        self.flash
+    }
+
+    /// Sets the colour added to the sprite after the tint, by its alpha.
+    ///
+    /// Only the visible parts of the sprite are flashed, so an opaque white
+    /// flash draws the sprite's silhouette in white.
+    pub fn set_flash(&mut self, flash: Color) {
+        self.flash = flash;
+    }
+
+    /// The opacity of the sprite, from `0` to `1`.
+    pub fn opacity(&self) -> f32 {
+        self.opacity
+    }
+
+    /// Sets the opacity of the sprite, from `0` to `1`. This is on top of the
+    /// alpha of the tint.
+    pub fn set_opacity(&mut self, opacity: f32) {
+        self.opacity = opacity;
+    }
+
+    /// The colour-remap texture the sprite is drawn through, if any.
+    pub fn palette(&self) -> Option<&Texture> {
+        self.palette.as_ref()
+    }
+
+    /// Sets the colour-remap texture the sprite is drawn through. See
+    /// [`bftd_lib::character::Palette`] for how it is laid out.
+    pub fn set_palette(&mut self, palette: Option<Texture>) {
+        self.palette = palette;
+    }
 }
 
 impl Debug for Sprite {
@@ -162,6 +246,10 @@ impl Debug for Sprite {
         f.debug_struct("Sprite")
             .field("src", &self.src)
             .field("transform", &self.transform)
+            .field("tint", &self.tint)
+            .field("flash", &self.flash)
+            .field("opacity", &self.opacity)
+            .field("palette", &self.palette)
             .finish_non_exhaustive()
     }
 }
@@ -180,83 +268,50 @@ impl Drawable for Sprite {
         let x = (self.src.width() * self.texture.width() as f32)
             / (self.src.height() * self.texture.height() as f32);
 
-        // recreate transform matrix
-        let transform = renderer.clip
-            * renderer.world
-            * self.transform
-            * Affine2::from_scale(Vec2::new(x, 1.0));
-        let transform = Mat4::from_mat3(Mat3::from(transform));
-        let transform_ref: &[f32; 16] = transform.as_ref();
-        let transform = renderer
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("transform uniform"),
-                contents: bytemuck::cast_slice(transform_ref),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            });
-
-        // do the same for the tex coord transform
-        let tex_transform = Affine2::from_scale(Vec2::new(self.src.width(), self.src.height()))
-            * Affine2::from_translation(Vec2::new(self.src.left(), self.src.bottom()));
-        let tex_transform = Mat4::from_mat3(Mat3::from(tex_transform));
-        let tex_transform_ref: &[f32; 16] = tex_transform.as_ref();
-        let tex_transform = renderer
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("tex transform uniform"),
-                contents: bytemuck::cast_slice(tex_transform_ref),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            });
-
-        let texture_view = self
-            .texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor {
-                label: Some("texture"),
-                ..Default::default()
-            });
-
-        let bind_group = renderer
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &renderer.sprite.bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::Sampler(&renderer.sprite.sampler),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::TextureView(&texture_view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: transform.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: tex_transform.as_entire_binding(),
-                    },
-                ],
-                label: None,
-            });
+        let transform = self.transform * Affine2::from_scale(Vec2::new(x, 1.0));
+        let params = DrawParams {
+            tint: self.tint.with_alpha(self.tint.a * self.opacity),
+            flash: self.flash,
+            palette: self.palette.clone(),
+        };
 
-        let mut rpass = renderer
-            .encoder
-            .begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &renderer.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
-        rpass.set_pipeline(&renderer.cx.sprite.pipeline);
-        rpass.set_bind_group(0, &bind_group, &[]);
-        rpass.draw(0..6, 0..1);
+        renderer.draw_texture_with(&self.texture, self.src.clone(), transform, params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw() {
+        let mut sprite = Sprite::new(Texture::headless(32, 64));
+        sprite.set_tint(Color::rgba(1., 0.5, 0.5, 0.5));
+        sprite.set_flash(Color::WHITE);
+        sprite.set_opacity(0.5);
+
+        let mut renderer = Renderer::new(1.);
+        sprite.draw(&mut renderer);
+
+        // the opacity goes on top of the alpha of the tint
+        let [command] = renderer.commands() else {
+            panic!("expected a single command");
+        };
+        assert_eq!(command.tint, Color::rgba(1., 0.5, 0.5, 0.25));
+        assert_eq!(command.flash, Color::WHITE);
+        assert!(command.palette.is_none());
+
+        // the palette goes on the same command
+        let remap = Texture::headless(256, 16);
+        sprite.set_palette(Some(remap.clone()));
+
+        let mut renderer = Renderer::new(1.);
+        sprite.draw(&mut renderer);
+
+        let [command] = renderer.commands() else {
+            panic!("expected a single command");
+        };
+        assert_eq!(command.palette.as_ref().map(Texture::id), Some(remap.id()));
+        assert_eq!(command.flash, Color::WHITE);
     }
 }
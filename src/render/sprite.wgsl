@@ -1,6 +1,20 @@
+struct InstanceInput {
+    // the columns of the affine transform to clip space
+    @location(0) x_axis: vec2<f32>,
+    @location(1) y_axis: vec2<f32>,
+    @location(2) translation: vec2<f32>,
+    // left, bottom, width and height of the source rectangle
+    @location(3) src: vec4<f32>,
+    @location(4) tint: vec4<f32>,
+    // added after the tint, scaled by its alpha
+    @location(5) flash: vec4<f32>,
+};
+
 struct VertexOutput {
     @builtin(position) position: vec4<f32>,
-    @location(0) tex_coord: vec4<f32>,
+    @location(0) tex_coord: vec2<f32>,
+    @location(1) tint: vec4<f32>,
+    @location(2) flash: vec4<f32>,
 };
 
 @group(0)
@@ -11,30 +25,73 @@ var tex_sampler: sampler;
 @binding(1)
 var tex: texture_2d<f32>;
 
-@group(0)
-@binding(2)
-var<uniform> transform: mat4x4<f32>;
-
-@group(0)
-@binding(3)
-var<uniform> tex_transform: mat4x4<f32>;
-
 @vertex
-fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+fn vs_main(
+    @builtin(vertex_index) in_vertex_index: u32,
+    instance: InstanceInput
+) -> VertexOutput {
     let x = f32((i32(in_vertex_index) + 2) / 3 % 2);
     let y = f32((i32(in_vertex_index) + 1) / 3 % 2);
     let v = 1.0 - y;
     let position = vec2<f32>(x, y) - vec2<f32>(0.5, 0.5);
-    let tex_coord = vec2<f32>(x, v);
 
     var result: VertexOutput;
-    result.position = transform * vec4<f32>(position, 1.0, 1.0);
-    result.tex_coord = tex_transform * vec4<f32>(tex_coord, 1.0, 1.0);
+    result.position = vec4<f32>(
+        instance.x_axis * position.x + instance.y_axis * position.y + instance.translation,
+        0.0,
+        1.0
+    );
+    result.tex_coord = instance.src.xy + instance.src.zw * vec2<f32>(x, v);
+    result.tint = instance.tint;
+    result.flash = instance.flash;
     return result;
 }
 
+// tints a colour, then adds the flash where it isn't transparent
+fn shade(color: vec4<f32>, tint: vec4<f32>, flash: vec4<f32>) -> vec4<f32> {
+    let tinted = color * tint;
+    return vec4<f32>(min(tinted.rgb + flash.rgb * flash.a, vec3<f32>(1.0)), tinted.a);
+}
+
 @fragment
 fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(tex, tex_sampler, vertex.tex_coord.xy);
+    let color = textureSample(tex, tex_sampler, vertex.tex_coord);
+    return shade(color, vertex.tint, vertex.flash);
 }
 
+// the colour-remap texture of the palette drawn through, in the remap
+// pipeline. see `bftd_lib::character::Palette` for the layout
+@group(1)
+@binding(1)
+var remap: texture_2d<f32>;
+
+// encodes linear colours back to sRGB, which palettes are indexed by
+fn to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3<f32>(0.0031308));
+}
+
+fn remap_entry(r: i32, g: i32, b: i32, size: i32) -> vec3<f32> {
+    return textureLoad(remap, vec2<i32>(b * size + r, g), 0).rgb;
+}
+
+@fragment
+fn fs_remap(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(tex, tex_sampler, vertex.tex_coord);
+
+    let size = textureDimensions(remap).y;
+    let cell = clamp(to_srgb(color.rgb), vec3<f32>(0.0), vec3<f32>(1.0)) * f32(size - 1);
+    let low = vec3<i32>(floor(cell));
+    let high = min(low + vec3<i32>(1), vec3<i32>(size - 1));
+    let t = cell - floor(cell);
+
+    // blend the eight closest entries, red first, then green, then blue
+    let g0b0 = mix(remap_entry(low.x, low.y, low.z, size), remap_entry(high.x, low.y, low.z, size), t.x);
+    let g1b0 = mix(remap_entry(low.x, high.y, low.z, size), remap_entry(high.x, high.y, low.z, size), t.x);
+    let g0b1 = mix(remap_entry(low.x, low.y, high.z, size), remap_entry(high.x, low.y, high.z, size), t.x);
+    let g1b1 = mix(remap_entry(low.x, high.y, high.z, size), remap_entry(high.x, high.y, high.z, size), t.x);
+    let remapped = mix(mix(g0b0, g1b0, t.y), mix(g0b1, g1b1, t.y), t.z);
+
+    return shade(vec4<f32>(remapped, color.a), vertex.tint, vertex.flash);
+}
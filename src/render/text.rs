@@ -0,0 +1,325 @@
+//! Text rendering.
+//!
+//! Glyphs aren't textures of their own. The first time a glyph is drawn, it
+//! is rasterized into a [`GlyphCache`], a single texture shared by every
+//! font, so a whole screen of text is drawn from one texture. The
+//! [`Context`](super::Context) uploads the cache once a frame, if anything
+//! was added to it.
+
+use super::font::{Font, Glyph};
+use super::{Color, Drawable, Renderer, Texture};
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+use bftd_lib::Rect;
+use glam::f32::{Affine2, Vec2};
+
+/// A texture of rasterized glyphs.
+pub struct GlyphCache {
+    texture: Texture,
+    // allocated on the first glyph, so a renderer that draws no text is cheap
+    pixels: Vec<u8>,
+    // the source rectangle of each glyph, by font and character
+    glyphs: HashMap<(u64, char), Rect>,
+    // the shelf being filled
+    x: u32,
+    y: u32,
+    shelf: u32,
+    dirty: bool,
+}
+
+impl GlyphCache {
+    /// The width and height of the cache texture, in pixels.
+    pub const SIZE: u32 = 512;
+
+    /// Creates an empty cache, drawing glyphs to `texture`.
+    ///
+    /// The texture should be [`GlyphCache::SIZE`] pixels wide and tall.
+    pub fn new(texture: Texture) -> GlyphCache {
+        GlyphCache {
+            texture,
+            pixels: Vec::new(),
+            glyphs: HashMap::new(),
+            x: 0,
+            y: 0,
+            shelf: 0,
+            dirty: false,
+        }
+    }
+
+    /// The cache texture.
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    /// The source rectangle of a glyph of `font`, rasterizing it first if it
+    /// isn't cached yet.
+    ///
+    /// Returns `None` if the cache is full. Glyphs are never evicted, so this
+    /// only happens with an unreasonable amount of fonts.
+    pub fn get(&mut self, font: &Font, c: char, glyph: &Glyph) -> Option<Rect> {
+        if let Some(src) = self.glyphs.get(&(font.id(), c)) {
+            return Some(src.clone());
+        }
+
+        // leave a pixel around every glyph, so they don't bleed together
+        let (width, height) = (glyph.width + 1, glyph.height + 1);
+
+        if self.x + width > Self::SIZE {
+            self.x = 0;
+            self.y += self.shelf;
+            self.shelf = 0;
+        }
+
+        if width > Self::SIZE || self.y + height > Self::SIZE {
+            return None;
+        }
+
+        if self.pixels.is_empty() {
+            self.pixels = vec![0; (Self::SIZE * Self::SIZE * 4) as usize];
+        }
+
+        // white, so the tint is the colour of the text
+        for (i, coverage) in glyph.coverage().iter().enumerate() {
+            let x = self.x + i as u32 % glyph.width;
+            let y = self.y + i as u32 / glyph.width;
+            let offset = ((y * Self::SIZE + x) * 4) as usize;
+
+            self.pixels[offset..offset + 4].copy_from_slice(&[255, 255, 255, *coverage]);
+        }
+
+        let size = Self::SIZE as f32;
+        let src = Rect::new_wh(
+            self.x as f32 / size,
+            self.y as f32 / size,
+            glyph.width as f32 / size,
+            glyph.height as f32 / size,
+        );
+
+        self.x += width;
+        self.shelf = self.shelf.max(height);
+        self.dirty = true;
+        self.glyphs.insert((font.id(), c), src.clone());
+
+        Some(src)
+    }
+
+    /// The pixels of the cache, if glyphs were added since they were last
+    /// taken.
+    pub fn take_dirty(&mut self) -> Option<&[u8]> {
+        if std::mem::take(&mut self.dirty) {
+            Some(&self.pixels)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for GlyphCache {
+    /// Creates an empty cache with a headless texture.
+    fn default() -> GlyphCache {
+        GlyphCache::new(Texture::headless(Self::SIZE, Self::SIZE))
+    }
+}
+
+impl Debug for GlyphCache {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("GlyphCache")
+            .field("texture", &self.texture)
+            .field("glyphs", &self.glyphs.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A run of text.
+///
+/// Text is laid out from its origin, at the left end of the baseline of the
+/// first line, with each line below the last. It is drawn through the world
+/// transform like any sprite: in the world by default, or in screen space
+/// with the world transform set to [`Affine2::IDENTITY`], where the screen is
+/// `1` unit tall.
+///
+/// Characters missing from the font are drawn as `?`, or not at all if the
+/// font doesn't have that either.
+#[derive(Clone)]
+pub struct Text {
+    font: Arc<Font>,
+    string: String,
+    height: f32,
+    transform: Affine2,
+    color: Color,
+}
+
+impl Text {
+    /// Creates a new `Text`, with lines `height` units apart.
+    pub fn new(font: Arc<Font>, string: impl Into<String>, height: f32) -> Text {
+        Text {
+            font,
+            string: string.into(),
+            height,
+            transform: Affine2::IDENTITY,
+            color: Color::WHITE,
+        }
+    }
+
+    /// The text to draw.
+    pub fn string(&self) -> &str {
+        &self.string
+    }
+
+    /// Sets the text to draw.
+    pub fn set_string(&mut self, string: impl Into<String>) {
+        self.string = string.into();
+    }
+
+    /// The transformations applied to the text.
+    pub fn transform(&self) -> Affine2 {
+        self.transform
+    }
+
+    /// Sets the transformations applied to the text.
+    pub fn set_transform(&mut self, transform: Affine2) {
+        self.transform = transform;
+    }
+
+    /// The colour of the text.
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    /// Sets the colour of the text.
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+    }
+
+    /// The width of the widest line, in units.
+    pub fn width(&self) -> f32 {
+        let advance = self
+            .string
+            .lines()
+            .map(|line| {
+                line.chars()
+                    .filter_map(|c| self.glyph(c))
+                    .map(|g| g.advance)
+                    .sum::<i32>()
+            })
+            .max()
+            .unwrap_or(0);
+
+        advance as f32 * self.scale()
+    }
+
+    /// The size of a pixel of the font, in units.
+    fn scale(&self) -> f32 {
+        self.height / self.font.line_height().max(1) as f32
+    }
+
+    fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.font.glyph(c).or_else(|| self.font.glyph('?'))
+    }
+}
+
+impl Debug for Text {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Text")
+            .field("string", &self.string)
+            .field("height", &self.height)
+            .field("transform", &self.transform)
+            .field("color", &self.color)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drawable for Text {
+    fn draw(&self, renderer: &mut Renderer) {
+        let scale = self.scale();
+        let texture = renderer.glyphs.texture().clone();
+
+        for (row, line) in self.string.lines().enumerate() {
+            let mut pen = Vec2::new(0., -(row as f32) * self.height);
+
+            for c in line.chars() {
+                let glyph = match self.glyph(c) {
+                    Some(glyph) => glyph,
+                    None => continue,
+                };
+
+                if glyph.width > 0 && glyph.height > 0 {
+                    if let Some(src) = renderer.glyphs.get(&self.font, c, glyph) {
+                        let size = Vec2::new(glyph.width as f32, glyph.height as f32) * scale;
+                        let offset = Vec2::new(glyph.offset.0 as f32, glyph.offset.1 as f32);
+                        let center = pen + offset * scale + size / 2.;
+
+                        let transform = self.transform
+                            * Affine2::from_translation(center)
+                            * Affine2::from_scale(size);
+
+                        renderer.draw_texture(&texture, src, transform, self.color);
+                    }
+                }
+
+                pen.x += glyph.advance as f32 * scale;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw() {
+        let bdf = "\
+FONTBOUNDINGBOX 2 3 0 -1
+STARTCHAR a
+ENCODING 97
+DWIDTH 3 0
+BBX 2 2 0 0
+BITMAP
+C0
+C0
+ENDCHAR
+STARTCHAR question
+ENCODING 63
+DWIDTH 3 0
+BBX 2 3 0 -1
+BITMAP
+C0
+40
+80
+ENDCHAR
+";
+        let font = Arc::new(Font::parse(bdf.as_bytes()).unwrap());
+
+        // three pixels to a line, so a pixel is a unit
+        let text = Text::new(font, "aa\nb", 3.);
+        assert_eq!(text.width(), 6.);
+
+        let mut renderer = Renderer::new(1.);
+        renderer.set_transform(Affine2::from_scale(Vec2::new(0.5, 0.5)));
+        text.draw(&mut renderer);
+
+        // the missing `b` is drawn as a `?`
+        let commands = renderer.commands();
+        assert_eq!(commands.len(), 3);
+
+        // both `a`s are the same glyph in the cache
+        assert_eq!(commands[0].src, commands[1].src);
+        assert_ne!(commands[0].src, commands[2].src);
+
+        let size = GlyphCache::SIZE as f32;
+        assert_eq!(
+            commands[2].src,
+            Rect::new_wh(3. / size, 0., 2. / size, 3. / size)
+        );
+
+        // the second line is a line down, and the `?` hangs under it
+        let center = |i: usize| renderer.commands()[i].transform.translation;
+        assert_eq!(center(0), Vec2::new(1., 1.));
+        assert_eq!(center(1), Vec2::new(4., 1.));
+        assert_eq!(center(2), Vec2::new(1., -2.5));
+    }
+}